@@ -0,0 +1,67 @@
+use crate::{DecodeOptions, Image};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Decoded images keyed by path, an entry is decoded again once the file's
+/// modification time changes
+#[derive(Default)]
+pub struct ImageCache {
+    entries: HashMap<PathBuf, (SystemTime, Image)>,
+    opts: DecodeOptions,
+    hits: usize,
+}
+
+impl ImageCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(opts: DecodeOptions) -> Self {
+        Self {
+            opts,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the decoded image at `path`, decoding it only if it is not
+    /// cached or the file has been modified since
+    pub fn get<P: AsRef<Path>>(&mut self, path: P) -> io::Result<&Image> {
+        let path = path.as_ref();
+        let modified = std::fs::metadata(path)?.modified()?;
+
+        let fresh = matches!(self.entries.get(path), Some((cached, _)) if *cached == modified);
+        if fresh {
+            self.hits += 1;
+        } else {
+            let file = path.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "path is not valid UTF-8")
+            })?;
+            let image = Image::from_with_options(file, &self.opts)?;
+            self.entries.insert(path.to_owned(), (modified, image));
+        }
+
+        Ok(&self.entries[path].1)
+    }
+
+    /// Removes the cached image at `path`
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) {
+        self.entries.remove(path.as_ref());
+    }
+
+    /// Number of `get` calls served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,53 +1,164 @@
-use flate2::bufread::ZlibDecoder;
+use flate2::{
+    bufread::{DeflateDecoder, ZlibDecoder},
+    Crc,
+};
 use std::{
+    cell::RefCell,
+    collections::HashSet,
     fmt::Display,
-    io::{self, Read},
+    io::{self, Read, Write},
+    sync::OnceLock,
+    time::{Duration, Instant},
 };
 
 const PNG_HDR: &[u8] = &[137, 80, 78, 71, 13, 10, 26, 10];
 
+/// 5.3 - the largest data length a chunk may declare
+const MAX_CHUNK_LEN: usize = (1 << 31) - 1;
+
+/// how far into a file the PNG signature is searched for when scanning for it
+const SIGNATURE_SCAN_LIMIT: usize = 4096;
+
+/// most bytes the text of a zTXt or iTXt chunk may inflate to, so a small
+/// chunk can not expand into gigabytes of text
+const MAX_TEXT_LEN: usize = 1 << 20;
+
+/// most chunks a file may have unless `DecodeOptions::max_chunks` says
+/// otherwise, so files of countless tiny chunks can not tie up the parser
+pub const DEFAULT_MAX_CHUNKS: usize = 100_000;
+
+/// characters ordered from the least to the most dense
+pub const RAMP: &str = " `^\",:;Il!i~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$";
+
+/// the ink coverage of each RAMP character in DejaVu Sans Mono, measured over
+/// the whole character cell and scaled so the densest glyph (B) is 255
+const RAMP_COVERAGE: [u8; 66] = [
+    0, 20, 65, 64, 41, 50, 65, 164, 123, 80, 129, 56, 107, 29, 24, 116, 122, 122, 141, 142, 149,
+    103, 103, 114, 100, 100, 132, 138, 132, 102, 138, 157, 154, 128, 120, 131, 187, 145, 201, 144,
+    152, 136, 233, 226, 217, 184, 201, 174, 197, 199, 199, 200, 178, 182, 177, 164, 85, 222, 250,
+    249, 212, 232, 170, 255, 253, 193,
+];
+
 macro_rules! pngerr {
     ($($args:tt)*) => {
         return Err(io::Error::new(io::ErrorKind::InvalidData, format!($($args)*)));
     };
 }
 
+mod cache;
+mod stream;
+
+pub use cache::ImageCache;
+pub use stream::stream_render;
+
 struct ImageHelper {
     offset: usize,
     data: Vec<u8>,
+    lenient: bool,
+
+    /// number of chunks read so far and the most that may be read
+    chunks: usize,
+    max_chunks: usize,
 }
 
 impl ImageHelper {
-    fn from(file: &str) -> io::Result<Self> {
+    fn from(file: &str, opts: &DecodeOptions) -> io::Result<Self> {
         let data = std::fs::read(file)?;
-        let pnghdr = &data[0..8];
-        assert_eq!(pnghdr, PNG_HDR);
+        Self::from_bytes(data, opts)
+    }
+
+    fn from_bytes(data: Vec<u8>, opts: &DecodeOptions) -> io::Result<Self> {
+        if opts.scan_for_signature {
+            let start = data
+                .windows(PNG_HDR.len())
+                .take(SIGNATURE_SCAN_LIMIT)
+                .position(|window| window == PNG_HDR);
+            return match start {
+                Some(start) => Ok(Self::at(data, start + PNG_HDR.len(), opts)),
+                None => {
+                    pngerr!("PNG signature not found");
+                }
+            };
+        }
+
+        if data.get(..PNG_HDR.len()) != Some(PNG_HDR) {
+            pngerr!("invalid PNG signature");
+        }
 
-        Ok(Self { offset: 8, data })
+        Ok(Self::at(data, PNG_HDR.len(), opts))
     }
 
-    fn next<'a>(&'a mut self) -> io::Result<Option<Chunk<'a>>> {
-        let chunk = Chunk::new(self)?;
-        if let Chunk::IEND = chunk {
-            Ok(None)
-        } else {
-            Ok(Some(chunk))
+    /// Reads the chunks of `data` starting at `offset`
+    fn at(data: Vec<u8>, offset: usize, opts: &DecodeOptions) -> Self {
+        Self {
+            offset,
+            data,
+            lenient: opts.lenient,
+            chunks: 0,
+            max_chunks: opts.max_chunks.unwrap_or(DEFAULT_MAX_CHUNKS),
+        }
+    }
+
+    fn next<'a>(&'a mut self, diagnostics: &mut Vec<Diagnostic>) -> io::Result<Option<Chunk<'a>>> {
+        // files missing only the IEND chunk are otherwise complete
+        if self.offset == self.data.len() {
+            let message = "file ends without an IEND chunk".to_owned();
+            violation(self.lenient, diagnostics, Some("IEND"), message)?;
+            return Ok(None);
+        }
+
+        self.chunks += 1;
+        if self.chunks > self.max_chunks {
+            pngerr!("file has more than {} chunks", self.max_chunks);
+        }
+
+        // the chunk borrows the data, so IEND is recognised before parsing to
+        // look past it afterwards
+        if self.data.get(self.offset + 4..self.offset + 8) == Some(&b"IEND"[..]) {
+            Chunk::new(self, diagnostics)?;
+            self.check_after_iend(diagnostics)?;
+            return Ok(None);
         }
+
+        Chunk::new(self, diagnostics).map(Some)
+    }
+
+    /// Section 5.6 - IEND is the last chunk, so anything shaped like a chunk
+    /// after it is a violation. Other trailing bytes are left alone, as some
+    /// tools append unrelated data to the file.
+    fn check_after_iend(&self, diagnostics: &mut Vec<Diagnostic>) -> io::Result<()> {
+        let rest = &self.data[self.offset..];
+        if rest.len() < 12 {
+            return Ok(());
+        }
+
+        let len = read_u32_be(rest, 0)? as usize;
+        let chunk_type = &rest[4..8];
+        if !chunk_type.iter().all(u8::is_ascii_alphabetic) || len > rest.len() - 12 {
+            return Ok(());
+        }
+
+        let chunk_type = String::from_utf8_lossy(chunk_type);
+        let message = format!(
+            "{} chunk at byte {} comes after the IEND chunk",
+            chunk_type, self.offset
+        );
+        violation(self.lenient, diagnostics, Some(&chunk_type), message)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct PLTEEntry {
     _red: u8,
     _green: u8,
     _blue: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Transparancy {
     PaletteIndex(Vec<u8>),
     Greyscale(u16),
-    RGB(u16, u16, u16),
+    Rgb(u16, u16, u16),
 }
 
 impl Transparancy {
@@ -57,9 +168,7 @@ impl Transparancy {
         }
 
         let mut entries = data.to_owned();
-        for _ in data.len()..plte_len {
-            entries.push(255);
-        }
+        entries.resize(plte_len, 255);
 
         Ok(Self::PaletteIndex(entries))
     }
@@ -69,7 +178,7 @@ impl Transparancy {
             pngerr!("invalid tRNS chunk");
         }
 
-        Ok(Self::Greyscale(u16::from_be_bytes([data[0], data[1]])))
+        Ok(Self::Greyscale(read_u16_be(data, 0)?))
     }
 
     fn for_rgb(data: &[u8]) -> io::Result<Self> {
@@ -77,16 +186,16 @@ impl Transparancy {
             pngerr!("invalid tRNS chunk");
         }
 
-        Ok(Self::RGB(
-            u16::from_be_bytes([data[0], data[1]]),
-            u16::from_be_bytes([data[2], data[3]]),
-            u16::from_be_bytes([data[4], data[5]]),
+        Ok(Self::Rgb(
+            read_u16_be(data, 0)?,
+            read_u16_be(data, 2)?,
+            read_u16_be(data, 4)?,
         ))
     }
 }
 
-#[derive(Debug)]
-enum ColorType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorType {
     Greyscale,
     RGB,
     PaletteIndex,
@@ -94,6 +203,18 @@ enum ColorType {
     RGBA,
 }
 
+impl ColorType {
+    /// number of samples per pixel
+    fn channels(&self) -> usize {
+        match self {
+            Self::Greyscale | Self::PaletteIndex => 1,
+            Self::GreyscaleAlpha => 2,
+            Self::RGB => 3,
+            Self::RGBA => 4,
+        }
+    }
+}
+
 impl Display for ColorType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let color_type = match self {
@@ -107,691 +228,6827 @@ impl Display for ColorType {
     }
 }
 
-pub struct Img {
-    grid: Vec<Vec<u8>>,
+/// A valid combination of color type and bit depth - RFC 2083 (section 4.1.1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Gray1,
+    Gray2,
+    Gray4,
+    Gray8,
+    Gray16,
+    Rgb8,
+    Rgb16,
+    Indexed1,
+    Indexed2,
+    Indexed4,
+    Indexed8,
+    GrayAlpha8,
+    GrayAlpha16,
+    Rgba8,
+    Rgba16,
 }
 
-impl Img {
-    pub fn new(file: &str) -> io::Result<Self> {
-        let image = Image::from(file)?;
-        let mut grid = Vec::new();
-        let pixle_size = match image.color_type {
-            ColorType::Greyscale => {
-                if image.bit_depth == 16 {
-                    unimplemented!("only 8bit colors are supported");
-                } else {
-                    1
-                }
-            }
-            ColorType::RGB => {
-                if image.bit_depth == 8 {
-                    3
-                } else {
-                    unimplemented!("only 8bit colors are supported");
-                }
+impl PixelFormat {
+    /// The format for `color_type` at `bit_depth`, or an error if the PNG
+    /// specification does not allow that bit depth for the color type
+    pub fn new(color_type: ColorType, bit_depth: u8) -> io::Result<Self> {
+        let format = match (color_type, bit_depth) {
+            (ColorType::Greyscale, 1) => Self::Gray1,
+            (ColorType::Greyscale, 2) => Self::Gray2,
+            (ColorType::Greyscale, 4) => Self::Gray4,
+            (ColorType::Greyscale, 8) => Self::Gray8,
+            (ColorType::Greyscale, 16) => Self::Gray16,
+            (ColorType::RGB, 8) => Self::Rgb8,
+            (ColorType::RGB, 16) => Self::Rgb16,
+            (ColorType::PaletteIndex, 1) => Self::Indexed1,
+            (ColorType::PaletteIndex, 2) => Self::Indexed2,
+            (ColorType::PaletteIndex, 4) => Self::Indexed4,
+            (ColorType::PaletteIndex, 8) => Self::Indexed8,
+            (ColorType::GreyscaleAlpha, 8) => Self::GrayAlpha8,
+            (ColorType::GreyscaleAlpha, 16) => Self::GrayAlpha16,
+            (ColorType::RGBA, 8) => Self::Rgba8,
+            (ColorType::RGBA, 16) => Self::Rgba16,
+            (ColorType::Greyscale, _) => {
+                pngerr!(
+                    "PNG of {} color type must have bit depth of 1, 2, 4, 8, or 16",
+                    color_type
+                );
             }
-            ColorType::PaletteIndex => 1,
-            ColorType::GreyscaleAlpha => {
-                if image.bit_depth == 8 {
-                    2
-                } else {
-                    unimplemented!("only 8bit colors are supported");
-                }
+            (ColorType::PaletteIndex, _) => {
+                pngerr!(
+                    "PNG of {} color type must have bit depth of 1, 2, 4, or 8",
+                    color_type
+                );
             }
-            ColorType::RGBA => {
-                if image.bit_depth == 8 {
-                    4
-                } else {
-                    unimplemented!("only 8bit colors are supported");
-                }
+            (ColorType::RGB | ColorType::GreyscaleAlpha | ColorType::RGBA, _) => {
+                pngerr!(
+                    "PNG of {} color type must have bit depth of 8 or 16",
+                    color_type
+                );
             }
         };
 
-        for r in 0..image.height {
-            let mut row = Vec::new();
-            for c in 0..image.width {
-                let idx = (c * pixle_size) as usize + (r * image.width) as usize;
-                let value = match image.color_type {
-                    ColorType::Greyscale => image.data[idx],
-                    ColorType::RGB => {
-                        ((image.data[idx] as u32
-                            + image.data[idx + 1] as u32
-                            + image.data[idx + 2] as u32)
-                            / 3) as u8
-                    }
-                    ColorType::PaletteIndex => {
-                        let plte = image.plte.as_ref().unwrap();
-                        let entry = &plte[image.data[idx] as usize];
+        Ok(format)
+    }
 
-                        ((entry._red as u32 + entry._green as u32 + entry._blue as u32) / 3) as u8
-                    }
-                    ColorType::GreyscaleAlpha => {
-                        ((image.data[idx] as u16 + image.data[idx + 1] as u16) / 2) as u8
-                    }
-                    ColorType::RGBA => {
-                        ((image.data[idx] as u32
-                            + image.data[idx + 1] as u32
-                            + image.data[idx + 2] as u32
-                            + image.data[idx + 3] as u32)
-                            / 4) as u8
-                    }
-                };
-                row.push(value)
+    pub fn color_type(&self) -> ColorType {
+        match self {
+            Self::Gray1 | Self::Gray2 | Self::Gray4 | Self::Gray8 | Self::Gray16 => {
+                ColorType::Greyscale
             }
-            grid.push(row);
+            Self::Rgb8 | Self::Rgb16 => ColorType::RGB,
+            Self::Indexed1 | Self::Indexed2 | Self::Indexed4 | Self::Indexed8 => {
+                ColorType::PaletteIndex
+            }
+            Self::GrayAlpha8 | Self::GrayAlpha16 => ColorType::GreyscaleAlpha,
+            Self::Rgba8 | Self::Rgba16 => ColorType::RGBA,
         }
-
-        // Convert to new desired width while maintaining aspect ratio
-        grid = resize_image(grid, &image);
-        Ok(Self { grid })
     }
 
-    // TODO: Convert to greyscale
-    pub fn display(&self) {
-        let chars: Vec<char> =
-            " `^\",:;Il!i~+_-?][}{1)(|\\/tfjrxnuvczXYUJCLQ0OZmwqpdbkhao*#MW&8%B@$"
-                .chars()
-                .collect();
-        for row in &self.grid {
-            for darkness in row {
-                let idx = ((66u16 * *darkness as u16) / 255) as usize;
-                let idx = if idx > 65 { 65 } else { idx };
-                print!("{}", chars[idx]);
-            }
-            println!();
+    pub fn bit_depth(&self) -> u8 {
+        match self {
+            Self::Gray1 | Self::Indexed1 => 1,
+            Self::Gray2 | Self::Indexed2 => 2,
+            Self::Gray4 | Self::Indexed4 => 4,
+            Self::Gray8 | Self::Rgb8 | Self::Indexed8 | Self::GrayAlpha8 | Self::Rgba8 => 8,
+            Self::Gray16 | Self::Rgb16 | Self::GrayAlpha16 | Self::Rgba16 => 16,
         }
     }
-}
 
-fn resize_image(grid: Vec<Vec<u8>>, image: &Image) -> Vec<Vec<u8>> {
-    let aspect_ratio = image.width as f32 / image.height as f32;
-    let target_height = 150usize;
-    let vertical_skip = image.height as f32 / target_height as f32;
-    let target_width = (aspect_ratio * target_height as f32) as usize;
-    let horizontal_skip = image.width as f32 / target_width as f32;
-    let mut resized = Vec::new();
-    for r in 0..target_height {
-        let y = (r as f32 * vertical_skip) as usize;
-        let mut row = Vec::new();
-        for c in 0..target_width {
-            let x = (c as f32 * horizontal_skip) as usize;
-            row.push(grid[y][x]);
-        }
-        resized.push(row);
+    /// number of bits of a single pixel
+    pub fn bits_per_pixel(&self) -> usize {
+        self.color_type().channels() * self.bit_depth() as usize
+    }
+
+    /// number of bytes in a scanline of `width` pixels, excluding the filter
+    /// type byte
+    pub fn stride(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Section 6.1 - number of bytes per complete pixel, rounded up to one for
+    /// bit depths lower than 8
+    fn filter_bpp(&self) -> usize {
+        self.bits_per_pixel().div_ceil(8)
     }
-    resized
 }
 
-#[derive(Debug)]
-pub struct Image {
-    /// width in pixels
-    width: u32,
+pub struct Img {
+    /// the decoded image the planes below are built from
+    image: Image,
 
-    /// height in pixels
-    height: u32,
+    /// brightness of each pixel scaled to the full 16-bit range, built the
+    /// first time the art is drawn
+    grid: OnceLock<Vec<Vec<u16>>>,
 
-    /// the number of bits per sample or per palette index (not per pixel)
-    bit_depth: u8,
+    /// opacity of each pixel scaled to the full 16-bit range, for images
+    /// with any transparency, built the first time it is needed
+    alpha: OnceLock<Option<Vec<Vec<u16>>>>,
 
-    /// the interpretation of the image data
-    color_type: ColorType,
+    /// how the grid reduces the color of a pixel to its brightness
+    luma: LumaMethod,
+}
 
-    /// a boolean value to determine if image is interlaced or not
-    /// the only available interlace type is "Adam7 interlace"
-    interlaced: bool,
+impl Img {
+    pub fn new(file: &str) -> io::Result<Self> {
+        Self::with_options(file, &DecodeOptions::default())
+    }
 
-    /// actual image data
-    data: Vec<u8>,
+    pub fn with_options(file: &str, opts: &DecodeOptions) -> io::Result<Self> {
+        Self::with_progress(file, opts, |_| {})
+    }
 
-    /// palettes (PLTE chunk)
-    plte: Option<Vec<PLTEEntry>>,
+    /// Like `with_options`, calling `progress` with the percentage of the
+    /// image data decoded so far each time it goes up
+    pub fn with_progress<F: FnMut(u8)>(
+        file: &str,
+        opts: &DecodeOptions,
+        mut progress: F,
+    ) -> io::Result<Self> {
+        let mut last = None;
+        let mut report = |percent: u8| {
+            if last != Some(percent) {
+                last = Some(percent);
+                progress(percent);
+            }
+        };
 
-    /// background color (bKGD chunk)
-    background: Option<BKGD>,
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from(file, opts)?;
+        let mut image = Image::decode_chunks(&mut chunks, opts, diagnostics, &mut report)?;
+        let width = image.width as usize;
 
-    /// simple transparency (tRNS chunk)
-    transparancy: Option<Transparancy>,
-}
+        // 16-bit greyscale samples are big-endian pairs
+        let full_depth =
+            opts.precise_16bit && image.bit_depth == 16 && image.color_type == ColorType::Greyscale;
+        let samples = full_depth.then(|| {
+            image
+                .data
+                .chunks(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                .collect::<Vec<_>>()
+        });
 
-impl Image {
-    pub fn from(file: &str) -> io::Result<Self> {
-        let mut chunks = ImageHelper::from(file)?;
-        let mut image = Self {
-            width: 0,
-            height: 0,
-            bit_depth: 0,
-            color_type: ColorType::Greyscale,
-            interlaced: false,
-            data: Vec::new(),
-            plte: None,
-            background: None,
-            transparancy: None,
-        };
-        let mut compressed_data: Vec<u8> = Vec::new();
+        image.finish(opts, diagnostics)?;
 
-        while let Some(chunk) = chunks.next()? {
-            match chunk {
-                Chunk::IEND => {
-                    break;
-                }
-                Chunk::IHDR(ihdr) => {
-                    image.width = ihdr.width;
-                    image.height = ihdr.height;
-                    image.bit_depth = ihdr.bit_depth;
-                    image.color_type = ihdr.color_type;
-                    image.interlaced = ihdr.interlace_method;
-                }
-                Chunk::PLTE(plte) => {
-                    // 4.1.2 - There must not be more than one PLTE chunk.
-                    if image.plte.is_some() {
-                        pngerr!("PNG must not have more than one PLTE chunk");
-                    }
+        // the samples are gone once the data is finished, so that grid can not
+        // wait until it is drawn
+        let grid = samples.map(|samples| {
+            samples
+                .chunks(width.max(1))
+                .map(|row| row.to_vec())
+                .collect()
+        });
 
-                    if image.background.is_some() {
-                        pngerr!("bKGD chunk can not preceed a PLTE chunk");
-                    }
+        Ok(Self::from_parts(grid, image, opts.luma))
+    }
 
-                    image.plte = Some(plte);
-                }
-                Chunk::IDAT(data) => {
-                    compressed_data.extend(data);
-                }
-                Chunk::BKGD(background) => {
-                    if !compressed_data.is_empty() {
-                        pngerr!("bKGD chunk can not come after the IDAT chunk");
-                    }
+    /// Builds the grid by mapping each pixel's RGBA value to a grid value with
+    /// `mapper` instead of the built-in brightness formulas
+    pub fn from_with_mapper<F: Fn([u8; 4]) -> u8>(file: &str, mapper: F) -> io::Result<Self> {
+        let image = Image::from(file)?;
+        let pixels = image.to_rgba8();
+        let grid = pixels
+            .chunks(image.width.max(1) as usize)
+            .map(|row| row.iter().map(|pixel| widen(mapper(*pixel))).collect())
+            .collect();
 
-                    match image.color_type {
-                        ColorType::PaletteIndex => {
-                            if let BKGD::PaletteIndex(_) = background {
-                            } else {
-                                pngerr!(
-                                    "PNG with color type 3 can only have palette index bKGD chunk"
-                                );
-                            }
-                        }
-                        ColorType::Greyscale | ColorType::GreyscaleAlpha => {
-                            if let BKGD::Greyscale(_) = background {
-                            } else {
-                                pngerr!("PNG with color type 0 or 4 can only have grey bKGD chunk");
-                            }
-                        }
-                        ColorType::RGB | ColorType::RGBA => {
-                            if let BKGD::RGB(_, _, _) = background {
-                            } else {
-                                pngerr!("PNG with color type 2 or 6 can only have RGB bKGD chunk");
-                            }
-                        }
-                    }
-                    image.background = Some(background);
-                }
-                Chunk::CHRM
-                | Chunk::GAMA
-                | Chunk::HIST
-                | Chunk::PHYS
-                | Chunk::SBIT
-                | Chunk::TEXT
-                | Chunk::TIME
-                | Chunk::ZTXT => {
-                    // ignore - not important in our use-case
-                }
-                Chunk::TRNS(data) => match image.color_type {
-                    ColorType::PaletteIndex => {
-                        image.transparancy = Some(Transparancy::for_indexed_color(
-                            data,
-                            image.plte.as_ref().expect("missing PLTE chunk").len(),
-                        )?)
-                    }
-                    ColorType::Greyscale => {
-                        image.transparancy = Some(Transparancy::for_grayscale(data)?)
-                    }
-                    ColorType::RGB => image.transparancy = Some(Transparancy::for_rgb(data)?),
-                    ColorType::GreyscaleAlpha | ColorType::RGBA => {
-                        pngerr!("PNG with color types 4 or 6 can not have a tRNS chunk");
-                    }
-                },
-            }
+        Ok(Self::from_parts(Some(grid), image, LumaMethod::default()))
+    }
+
+    /// Wraps a decoded image, leaving the grid to be built from it on first
+    /// use unless one is given
+    fn from_parts(grid: Option<Vec<Vec<u16>>>, image: Image, luma: LumaMethod) -> Self {
+        let cell = OnceLock::new();
+        if let Some(grid) = grid {
+            let _ = cell.set(grid);
         }
 
-        // 4.1.2
-        // This chunk must appear for color type 3, and can appear for
-        // color types 2 and 6; it must not appear for color types 0 and
-        // 4. If this chunk does appear, it must precede the first IDAT
-        // chunk.
-        match image.color_type {
-            ColorType::Greyscale => {
-                // validate bit depth
-                if let 1 | 2 | 4 | 8 | 16 = image.bit_depth {
-                } else {
-                    pngerr!(
-                        "PNG of {} color type must have bit depth of 1, 2, 4, 8, or 16",
-                        image.color_type
-                    );
-                }
+        Self {
+            image,
+            grid: cell,
+            alpha: OnceLock::new(),
+            luma,
+        }
+    }
 
-                // validate PLTE chunk existance
-                if image.plte.is_some() {
-                    pngerr!(
-                        "PNG of {} color type cannot have a PLTE chunk",
-                        image.color_type
-                    );
-                }
-            }
-            ColorType::GreyscaleAlpha => {
-                // validate bit depth
-                if let 8 | 16 = image.bit_depth {
-                } else {
-                    pngerr!(
-                        "PNG of {} color type must have bit depth of 8 or 16",
-                        image.color_type
-                    );
-                }
+    /// The decoded image behind the art
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
 
-                // validate PLTE chunk existance
-                if image.plte.is_some() {
-                    pngerr!(
-                        "PNG of {} color type cannot have a PLTE chunk",
-                        image.color_type
-                    );
-                }
-            }
-            ColorType::PaletteIndex => {
-                // validate bit depth
-                if let 1 | 2 | 4 | 8 = image.bit_depth {
-                } else {
-                    pngerr!(
-                        "PNG of {} color type must have bit depth of 1, 2, 4, or 8",
-                        image.color_type
-                    );
-                }
+    /// The brightness grid, built from the image data the first time it is
+    /// asked for
+    fn grid(&self) -> &Vec<Vec<u16>> {
+        self.grid.get_or_init(|| {
+            let image = &self.image;
+            let width = image.width as usize;
+            let stride = width * image.color_type.channels();
+            (0..image.height as usize)
+                .map(|r| {
+                    let scanline = &image.data[r * stride..(r + 1) * stride];
+                    (0..width)
+                        .map(|c| widen(image.grey_at(scanline, c, self.luma)))
+                        .collect()
+                })
+                .collect()
+        })
+    }
 
-                // validate PLTE chunk existance
-                if image.plte.is_none() {
-                    pngerr!(
-                        "PNG of {} color type must have a PLTE chunk",
-                        image.color_type
-                    );
-                }
+    /// The opacity plane, built the first time it is asked for
+    fn alpha(&self) -> Option<&Vec<Vec<u16>>> {
+        self.alpha
+            .get_or_init(|| {
+                let image = &self.image;
+                let has_alpha = matches!(
+                    image.color_type,
+                    ColorType::GreyscaleAlpha | ColorType::RGBA
+                ) || image.transparancy.is_some();
+                has_alpha.then(|| {
+                    image
+                        .to_rgba8()
+                        .chunks(image.width.max(1) as usize)
+                        .map(|row| row.iter().map(|pixel| widen(pixel[3])).collect())
+                        .collect()
+                })
+            })
+            .as_ref()
+    }
 
-                // validate palette entry length
-                let bit_depth_range = 2usize.pow(image.bit_depth as u32);
-                if image.plte.as_ref().unwrap().len() > bit_depth_range {
-                    pngerr!(
-                        "PNG of {} color type can not have more entries that its bit depth range",
-                        image.color_type
-                    );
-                }
-            }
-            ColorType::RGB | ColorType::RGBA => {
-                // validate bit depth
-                if let 8 | 16 = image.bit_depth {
-                } else {
-                    pngerr!(
-                        "PNG of {} color type must have bit depth of 1, 2, 4, or 8",
-                        image.color_type
-                    );
-                }
-            }
+    /// Adjusts the grid values before rendering: `brightness` is added to each
+    /// value, `contrast` scales the distance from mid-grey, and `gamma` applies a
+    /// power curve where values above 1 brighten. Each step clamps to 0-255.
+    pub fn adjust(&mut self, brightness: i16, contrast: f32, gamma: f32) {
+        let mut lut = vec![0u16; 1 << 16];
+        for (value, adjusted) in lut.iter_mut().enumerate() {
+            let v = (value as f32 / 257.0 + brightness as f32).clamp(0.0, 255.0);
+            let v = ((v - 128.0) * contrast + 128.0).clamp(0.0, 255.0);
+            let v = if gamma > 0.0 {
+                255.0 * (v / 255.0).powf(1.0 / gamma)
+            } else {
+                v
+            };
+            *adjusted = (v * 257.0).round().clamp(0.0, 65535.0) as u16;
         }
 
-        // decompress data
-        let mut decoder = ZlibDecoder::new(&compressed_data[..]);
-        let mut filtered = Vec::new();
-        decoder.read_to_end(&mut filtered)?;
-
-        reverse_filter(filtered, &mut image)?;
+        self.grid();
+        let grid = self.grid.get_mut().expect("the grid was just built");
+        for value in grid.iter_mut().flatten() {
+            *value = lut[*value as usize];
+        }
+    }
 
-        convert_bit_depth(&mut image);
+    // TODO: Convert to greyscale
+    pub fn display(&self) {
+        self.render(&mut io::stdout(), &RenderOptions::default())
+            .expect("failed to write to stdout");
+    }
 
-        Ok(image)
+    /// Displays the image at a size matching `dpi` characters per inch based on
+    /// the physical pixel dimensions, see `physical_size`
+    pub fn display_physical(&self, dpi: f32) {
+        let opts = RenderOptions {
+            size: self.physical_size(dpi),
+            ..Default::default()
+        };
+        self.render(&mut io::stdout(), &opts)
+            .expect("failed to write to stdout");
     }
-}
 
-/// Converts from 16-bit colors to 8-bit colors
-fn convert_bit_depth(image: &mut Image) {
-    if image.bit_depth != 16 {
-        return;
+    /// Displays the image at the largest size that keeps its aspect ratio
+    /// within `max_chars` characters in total, see `budget_size`
+    pub fn display_budget(&self, max_chars: usize) {
+        let opts = RenderOptions {
+            size: self.budget_size(max_chars),
+            ..Default::default()
+        };
+        self.render(&mut io::stdout(), &opts)
+            .expect("failed to write to stdout");
     }
 
-    let mut converted = Vec::new();
-    for idx in 0..image.data.len() {
-        if idx % 2 == 0 {
-            converted
-                .push((u16::from_be_bytes([image.data[idx], image.data[idx + 1]]) / 256u16) as u8);
+    /// Largest output size keeping the image's aspect ratio whose number of
+    /// columns times rows is at most `max_chars`
+    pub fn budget_size(&self, max_chars: usize) -> Size {
+        let (width, height) = self.dimensions();
+        if width == 0 || height == 0 {
+            return Size::Exact(0, 0);
         }
-    }
 
-    image.data = converted;
-    image.bit_depth = 8;
-}
+        let aspect_ratio = width as f32 / height as f32;
+        let rows = (max_chars as f32 / aspect_ratio).sqrt() as usize;
 
-#[derive(Debug)]
-enum FilterType {
-    None,
-    Sub,
-    Up,
-    Average,
-    Paeth,
-}
+        // truncating the columns can leave room for another row
+        (rows.saturating_sub(1)..=rows + 1)
+            .map(|rows| ((aspect_ratio * rows as f32) as usize, rows))
+            .filter(|(cols, rows)| cols * rows <= max_chars)
+            .max_by_key(|(cols, rows)| cols * rows)
+            .map_or(Size::Exact(0, 0), |(cols, rows)| Size::Exact(cols, rows))
+    }
 
-impl FilterType {
-    fn from(byte: u8) -> io::Result<Self> {
-        match byte {
-            0 => Ok(Self::None),
-            1 => Ok(Self::Sub),
-            2 => Ok(Self::Up),
-            3 => Ok(Self::Average),
-            4 => Ok(Self::Paeth),
-            _ => {
-                pngerr!("invalid filter type");
+    /// Output size that reproduces the image's physical dimensions at `dpi`
+    /// characters per inch, stretching each axis independently so non-square
+    /// pixels come out with the correct proportions. Images without a pHYs
+    /// chunk in metres are assumed to be 72 DPI.
+    pub fn physical_size(&self, dpi: f32) -> Size {
+        let (x_ppm, y_ppm) = match self.image.physical {
+            Some(PHYSData {
+                x_ppu,
+                y_ppu,
+                unit_is_meter: true,
+            }) if x_ppu > 0 && y_ppu > 0 => (x_ppu as f32, y_ppu as f32),
+            // without a unit only the pixel aspect ratio is known
+            Some(PHYSData { x_ppu, y_ppu, .. }) if x_ppu > 0 && y_ppu > 0 => {
+                (DEFAULT_PPM, DEFAULT_PPM * y_ppu as f32 / x_ppu as f32)
             }
-        }
+            _ => (DEFAULT_PPM, DEFAULT_PPM),
+        };
+
+        let (width, height) = self.dimensions();
+        let inches_per_meter = 39.37;
+        let cols = width as f32 / x_ppm * inches_per_meter * dpi;
+        let rows = height as f32 / y_ppm * inches_per_meter * dpi;
+        Size::Exact(cols.round() as usize, rows.round() as usize)
     }
-}
 
-/// RFC 2083 - Section 6
-fn reverse_filter(filtered: Vec<u8>, image: &mut Image) -> io::Result<()> {
-    let width = filtered.len() / image.height as usize;
-    let bpp = match image.color_type {
-        ColorType::Greyscale => {
-            if image.bit_depth == 16 {
-                2
-            } else {
-                1
-            }
-        }
-        ColorType::RGB => {
-            if image.bit_depth == 8 {
-                3
-            } else {
-                6
-            }
-        }
-        ColorType::PaletteIndex => 1,
-        ColorType::GreyscaleAlpha => {
-            if image.bit_depth == 8 {
-                2
-            } else {
-                4
-            }
-        }
-        ColorType::RGBA => {
-            if image.bit_depth == 8 {
-                4
-            } else {
-                8
-            }
+    /// Writes the ASCII art to `w` according to `opts`, returning the number
+    /// of columns and rows of the art
+    pub fn render<W: Write>(&self, w: &mut W, opts: &RenderOptions) -> io::Result<(usize, usize)> {
+        self.write_rows(w, &self.ascii_rows(opts), opts)
+    }
+
+    /// Renders like `render` with `label` stamped over the art starting at
+    /// `row` and `col`, overwriting those characters. The label is moved back
+    /// inside the art when it would not fit and cut to the art's width.
+    pub fn render_with_label<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+        label: &str,
+        row: usize,
+        col: usize,
+    ) -> io::Result<(usize, usize)> {
+        let mut rows = self.ascii_rows(opts);
+        let (cols, height) = art_dimensions(&rows);
+        if height > 0 {
+            let label: Vec<char> = label.chars().take(cols).collect();
+            let row = row.min(height - 1);
+            let col = col.min(cols - label.len());
+
+            let mut chars: Vec<char> = rows[row].chars().collect();
+            chars[col..col + label.len()].copy_from_slice(&label);
+            rows[row] = chars.into_iter().collect();
         }
-    };
 
-    for r in 0..image.height as usize {
-        let offset = r * width;
-        match FilterType::from(filtered[r * width])? {
-            FilterType::None => {
-                image
-                    .data
-                    .extend(&filtered[((r * width) + 1)..((r * width) + width)]);
-            }
-            FilterType::Sub => {
-                // CHECK: Section 6.3: Raw(x) = Sub(x) + Raw(x-bpp)
-                for c in 1..width {
-                    let x = c + offset;
-                    let raw_x = if c <= bpp {
-                        filtered[x]
-                    } else {
-                        // (x - (r + 1)) because x is the index in the filtered array however we want the
-                        // index of the raw array. (r + 1) is the number of filter bytes.
-                        let raw_idx = x - (r + 1);
-                        filtered[x].wrapping_add(image.data[raw_idx - bpp])
-                    };
-                    image.data.push(raw_x);
-                }
+        self.write_rows(w, &rows, opts)
+    }
+
+    /// Renders the art scaled to fit inside a canvas of `cols`x`rows`
+    /// characters, keeping its aspect ratio, centered with spaces padding
+    /// the rest so differently shaped images line up in a grid. The size in
+    /// `opts` is replaced by the fitted one.
+    pub fn render_canvas<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+        cols: usize,
+        rows: usize,
+    ) -> io::Result<(usize, usize)> {
+        let (width, height) = self.dimensions();
+
+        // each grid column takes two cells when doubled or full width
+        let cells = if opts.full_width_cells { 2 } else { 1 };
+        let repeat = if opts.char_aspect_double { 2 } else { 1 };
+        let budget = cols / (cells * repeat);
+
+        let fitted = RenderOptions {
+            size: Size::Height(rows),
+            ..opts.clone()
+        };
+        let (fit_cols, fit_rows) = match fitted.dimensions(width, height) {
+            (fit_cols, _) if fit_cols > budget => {
+                let fit_rows = rows as f32 * budget as f32 / fit_cols as f32;
+                (budget, (fit_rows as usize).max(1))
             }
-            FilterType::Up => {
-                // CHECK: Section 6.4: Raw(x) = Up(x) + Prior(x)
-                for c in 1..width {
-                    let x = c + offset;
-                    let raw_x = if r == 0 {
-                        filtered[x]
-                    } else {
-                        let raw_idx = x - (r + 1);
-                        filtered[x].wrapping_add(image.data[raw_idx - (width - 1)])
-                    };
-                    image.data.push(raw_x);
-                }
-            }
-            FilterType::Average => {
-                // CHECK: Section 6.5: Raw(x) = Average(x) + floor((Raw(x-bpp)+Prior(x))/2)
-                for c in 1..width {
-                    let x = c + offset;
-                    let raw_idx = x - (r + 1);
-                    let raw_x = match (r == 0, c <= bpp) {
-                        (true, true) => filtered[x],
-                        (true, false) => filtered[x].wrapping_add(image.data[raw_idx - bpp]),
-                        (false, true) => {
-                            filtered[x].wrapping_add(image.data[raw_idx - (width - 1)])
-                        }
-                        (false, false) => filtered[x].wrapping_add(
-                            ((image.data[raw_idx - bpp] as u16
-                                + image.data[raw_idx - (width - 1)] as u16)
-                                / 2) as u8,
-                        ),
-                    };
-                    image.data.push(raw_x);
+            dimensions => dimensions,
+        };
+        let fitted = RenderOptions {
+            size: Size::Exact(fit_cols, fit_rows),
+            ..fitted
+        };
+
+        let art = self.ascii_rows(&fitted);
+        let (art_cols, art_rows) = art_dimensions(&art);
+        let art_cols = art_cols * cells;
+        let (left, top) = (
+            cols.saturating_sub(art_cols) / 2,
+            rows.saturating_sub(art_rows) / 2,
+        );
+        let blank = " ".repeat(cols);
+        let canvas: Vec<String> = (0..rows)
+            .map(|r| match r.checked_sub(top).and_then(|r| art.get(r)) {
+                Some(row) => {
+                    let right = cols.saturating_sub(left + art_cols);
+                    format!("{}{}{}", &blank[..left], row, &blank[..right])
                 }
+                None => blank.clone(),
+            })
+            .collect();
+
+        self.write_rows(w, &canvas, opts)
+    }
+
+    /// Renders the art with one column per pixel and one row per `v_factor`
+    /// rows of pixels, writing each character `h_repeat` times, so the aspect
+    /// ratio can be corrected for any character cell shape. Returns the
+    /// number of columns and rows of the art.
+    pub fn render_aspect<W: Write>(
+        &self,
+        h_repeat: usize,
+        v_factor: usize,
+        w: &mut W,
+    ) -> io::Result<(usize, usize)> {
+        let (width, height) = self.dimensions();
+        let opts = RenderOptions {
+            size: Size::Exact(width, height.div_ceil(v_factor.max(1))),
+            ..Default::default()
+        };
+
+        let rows: Vec<String> = self
+            .ascii_rows(&opts)
+            .iter()
+            .map(|row| {
+                row.chars()
+                    .flat_map(|ch| std::iter::repeat_n(ch, h_repeat))
+                    .collect()
+            })
+            .collect();
+        self.write_rows(w, &rows, &opts)
+    }
+
+    /// Writes rendered rows followed by the caption and legend
+    fn write_rows<W: Write>(
+        &self,
+        w: &mut W,
+        rows: &[String],
+        opts: &RenderOptions,
+    ) -> io::Result<(usize, usize)> {
+        let (cols, _) = art_dimensions(rows);
+        let cells = if opts.full_width_cells { 2 } else { 1 };
+        let edge = "─".repeat(cols * cells);
+        let background = self.background(opts).filter(|_| !opts.no_ansi);
+        let trim = opts.trim_trailing_spaces && !self.fully_transparent();
+        if opts.border {
+            write_line(w, &format!("┌{}┐", edge), background)?;
+        }
+        for row in rows {
+            if opts.border {
+                write_line(w, &format!("│{}│", row), background)?;
+            } else if trim {
+                write_line(w, row.trim_end_matches([' ', '\u{3000}']), background)?;
+            } else {
+                write_line(w, row, background)?;
             }
-            FilterType::Paeth => {
-                // CHECK: Section 6.6: Raw(x) = Paeth(x) + PaethPredictor(Raw(x-bpp), Prior(x), Prior(x-bpp))
-                for c in 1..width {
-                    let x = c + offset;
-                    let raw_idx = x - (r + 1);
-                    let left = if c <= bpp {
-                        0
-                    } else {
-                        image.data[raw_idx - bpp]
-                    };
-                    let top = if r == 0 {
-                        0
-                    } else {
-                        image.data[raw_idx - (width - 1)]
-                    };
-                    let top_left = if r == 0 || c <= bpp {
-                        0
-                    } else {
-                        image.data[raw_idx - (width - 1) - bpp]
-                    };
+        }
+        if opts.border {
+            write_line(w, &format!("└{}┘", edge), background)?;
+        }
+
+        if let Some(caption) = self.caption(opts) {
+            writeln!(w, "{}", caption)?;
+        }
+
+        if opts.with_legend {
+            write_legend(w)?;
+        }
+
+        Ok(art_dimensions(rows))
+    }
+
+    /// Writes the ASCII art as an HTML page, returning the number of columns
+    /// and rows of the art
+    pub fn render_html<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+    ) -> io::Result<(usize, usize)> {
+        let rows = self.ascii_rows(opts);
+        let style = match self.background(opts) {
+            Some([r, g, b]) => format!(" style=\"background-color: #{:02x}{:02x}{:02x}\"", r, g, b),
+            None => String::new(),
+        };
+        writeln!(
+            w,
+            "<html>
+    <body{}>
+        <div style=\"line-height: 10px; font-size: 14px\">
+            <pre>",
+            style
+        )?;
+        for row in &rows {
+            writeln!(w, "{}", escape_xml(row))?;
+        }
+        if opts.with_legend {
+            let mut legend = Vec::new();
+            write_legend(&mut legend)?;
+            write!(w, "{}", escape_xml(&String::from_utf8_lossy(&legend)))?;
+        }
+        writeln!(w, "</pre>")?;
+        if let Some(caption) = self.caption(opts) {
+            writeln!(w, "            <p>{}</p>", escape_xml(caption))?;
+        }
+        writeln!(
+            w,
+            "        </div>
+    </body>
+</html>"
+        )?;
+
+        Ok(art_dimensions(&rows))
+    }
+
+    /// Writes the ASCII art as an SVG document with one `<text>` element per
+    /// row, `font_size` is in pixels. Returns the number of columns and rows of
+    /// the art.
+    pub fn render_svg<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+        font_size: u32,
+    ) -> io::Result<(usize, usize)> {
+        let rows = self.ascii_rows(opts);
+        let (cols, _) = art_dimensions(&rows);
+        let caption = self.caption(opts);
+
+        // monospace glyphs are roughly 0.6em wide
+        let width = (cols as f32 * font_size as f32 * 0.6).ceil() as u32;
+        let lines = rows.len() + caption.map_or(0, |_| 1);
+        let height = lines as u32 * font_size;
+        writeln!(
+            w,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             font-family=\"monospace\" font-size=\"{font_size}\" xml:space=\"preserve\">"
+        )?;
+        writeln!(w, "<rect width=\"100%\" height=\"100%\" fill=\"black\"/>")?;
+        for (r, row) in rows.iter().enumerate() {
+            writeln!(
+                w,
+                "<text x=\"0\" y=\"{}\" fill=\"white\">{}</text>",
+                (r as u32 + 1) * font_size,
+                escape_xml(row)
+            )?;
+        }
+        if let Some(caption) = caption {
+            writeln!(
+                w,
+                "<text x=\"0\" y=\"{}\" fill=\"white\">{}</text>",
+                lines as u32 * font_size,
+                escape_xml(caption)
+            )?;
+        }
+        writeln!(w, "</svg>")?;
+
+        Ok(art_dimensions(&rows))
+    }
+
+    /// The ASCII art as a newline separated string
+    pub fn to_ascii_string(&self, opts: &RenderOptions) -> String {
+        let mut out = Vec::new();
+        self.render(&mut out, opts)
+            .expect("writing to a Vec can not fail");
+        String::from_utf8(out).expect("rendered art is valid UTF-8")
+    }
 
-                    let predictor = paeth_predictor(left, top, top_left);
-                    image.data.push(filtered[x].wrapping_add(predictor));
+    /// The ASCII art rows with each run of identical characters collapsed
+    /// into the character and its count, for compact storage or transmission.
+    /// The border, caption, and legend are not included.
+    pub fn to_runs(&self, opts: &RenderOptions) -> Vec<RunLength> {
+        self.ascii_rows(opts)
+            .iter()
+            .map(|row| {
+                let mut runs: RunLength = Vec::new();
+                for ch in row.chars() {
+                    match runs.last_mut() {
+                        Some((last, count)) if *last == ch => *count += 1,
+                        _ => runs.push((ch, 1)),
+                    }
                 }
+                runs
+            })
+            .collect()
+    }
+
+    /// The grid resized to `cols`x`rows` as on/off cells for monochrome
+    /// displays, a cell is on when its value is at least `threshold`
+    pub fn to_bitmap(&self, threshold: u8, cols: usize, rows: usize) -> Vec<Vec<bool>> {
+        let threshold = widen(threshold);
+        resize_image(self.grid(), cols, rows)
+            .into_iter()
+            .map(|row| row.into_iter().map(|value| value >= threshold).collect())
+            .collect()
+    }
+
+    /// A fingerprint of the rendered characters for regression tests, stable
+    /// across runs and builds. Captions and the legend are not included.
+    pub fn ascii_digest(&self, opts: &RenderOptions) -> u64 {
+        // 64-bit FNV-1a over the rows, each terminated by a newline
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for row in self.ascii_rows(opts) {
+            for byte in row.bytes().chain([b'\n']) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
             }
         }
+        hash
     }
 
-    Ok(())
-}
+    /// The ASCII art and its dimensions as a JSON object of the form
+    /// `{"width":w,"height":h,"rows":["...","..."]}`
+    pub fn to_json(&self, opts: &RenderOptions) -> String {
+        let rows = self.ascii_rows(opts);
+        let (width, height) = art_dimensions(&rows);
+        let rows: Vec<String> = rows.iter().map(|row| escape_json(row)).collect();
+        format!(
+            "{{\"width\":{},\"height\":{},\"rows\":[{}]}}",
+            width,
+            height,
+            rows.join(",")
+        )
+    }
 
-fn paeth_predictor(left: u8, top: u8, top_left: u8) -> u8 {
-    let p = left as i16 + top as i16 - top_left as i16;
-    let pleft = 0i16.abs_diff(p - left as i16);
-    let ptop = 0i16.abs_diff(p - top as i16);
-    let ptop_left = 0i16.abs_diff(p - top_left as i16);
+    /// The bKGD color, if it is to be drawn behind the art
+    fn background(&self, opts: &RenderOptions) -> Option<[u8; 3]> {
+        self.image
+            .background_rgb()
+            .filter(|_| opts.background_from_metadata)
+    }
 
-    if pleft <= ptop && pleft <= ptop_left {
-        left
-    } else if ptop <= ptop_left {
-        top
-    } else {
-        top_left
+    /// The first Title or Description text entry, if captions are enabled
+    fn caption(&self, opts: &RenderOptions) -> Option<&str> {
+        if !opts.caption_from_metadata {
+            return None;
+        }
+
+        self.image
+            .text
+            .iter()
+            .find(|entry| entry.keyword == "Title" || entry.keyword == "Description")
+            .map(|entry| entry.text.as_str())
     }
-}
 
-impl Display for Image {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Dimention: {}x{}px\nColor Type: {}\nBit Depth: {}\nInterlaced: {}\nData Size: {} bytes",
-            self.width,
-            self.height,
-            self.color_type,
-            self.bit_depth,
-            if self.interlaced { "Yes" } else { "No" },
-            self.data.len()
-        )
+    /// Writes the art at `levels` sizes stacked vertically, starting at the
+    /// size from `opts` and halving it at each level, every level preceeded
+    /// by a line with its columns and rows. Levels stop early once the art
+    /// is a single character. Borders, captions, and the legend are left out.
+    /// Returns the number of levels written.
+    pub fn render_mipmap<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+        levels: usize,
+    ) -> io::Result<usize> {
+        let (width, height) = self.dimensions();
+        let (mut cols, mut rows) = opts.dimensions(width, height);
+        for level in 0..levels {
+            let level_opts = RenderOptions {
+                size: Size::Exact(cols, rows),
+                ..opts.clone()
+            };
+            writeln!(w, "{}x{}", cols, rows)?;
+            for row in self.ascii_rows(&level_opts) {
+                writeln!(w, "{}", row)?;
+            }
+
+            if cols <= 1 && rows <= 1 {
+                return Ok(level + 1);
+            }
+            cols = (cols / 2).max(1);
+            rows = (rows / 2).max(1);
+        }
+
+        Ok(levels)
     }
-}
 
-#[derive(Debug)]
-enum BKGD {
-    PaletteIndex(u8),
-    Greyscale(u16),
-    RGB(u16, u16, u16),
+    /// Writes the grid to `w` as a heat map of full block characters colored
+    /// by brightness on the viridis scale, dark values purple and bright ones
+    /// yellow, using 24-bit ANSI colors. Each block stands for the value a
+    /// character of the art would, so only the options of `opts` that size
+    /// and sample the art apply, unless `no_ansi` is set and the art is
+    /// rendered as usual. Returns the number of columns and rows of the map.
+    pub fn render_heatmap<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+    ) -> io::Result<(usize, usize)> {
+        if opts.no_ansi {
+            return self.render(w, opts);
+        }
+
+        let grid = self.art_grid(opts);
+        for row in &grid {
+            let mut line = String::new();
+            for value in row {
+                let [r, g, b] = viridis(*value);
+                line.push_str(&format!("\x1b[38;2;{};{};{}m█", r, g, b));
+            }
+            writeln!(w, "{}\x1b[0m", line)?;
+        }
+
+        Ok((grid.first().map_or(0, |row| row.len()), grid.len()))
+    }
+
+    /// Writes only the alpha channel as ASCII art to `w`, opaque pixels as
+    /// the densest characters and transparent ones as spaces, returning the
+    /// number of columns and rows of the art. Images without transparency
+    /// are rendered as fully opaque.
+    pub fn render_alpha<W: Write>(
+        &self,
+        w: &mut W,
+        opts: &RenderOptions,
+    ) -> io::Result<(usize, usize)> {
+        let alpha = match self.alpha() {
+            Some(alpha) => self.downscaled(alpha, opts),
+            None => {
+                let (width, height) = self.dimensions();
+                let (cols, rows) = opts.dimensions(width, height);
+                vec![vec![u16::MAX; cols]; rows]
+            }
+        };
+        self.write_rows(w, &ramp_rows(&alpha, None, opts), opts)
+    }
+
+    /// Writes every channel of the image as its own ASCII art stacked
+    /// vertically, each preceeded by a line with the channel's name, for
+    /// images whose channels hold something other than color such as normal
+    /// maps or CMYK. Greyscale images have a single grey channel, the others
+    /// red, green, and blue, followed by alpha for images with transparency.
+    /// Borders, captions, and the legend are left out. Returns the number of
+    /// channels written. For one combination of the channels, see
+    /// `from_with_mapper`.
+    pub fn render_channels<W: Write>(&self, w: &mut W, opts: &RenderOptions) -> io::Result<usize> {
+        let mut channels = match self.image.color_type {
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => vec![("grey", 0)],
+            _ => vec![("red", 0), ("green", 1), ("blue", 2)],
+        };
+        if self.alpha().is_some() {
+            channels.push(("alpha", 3));
+        }
+
+        let pixels = self.image.to_rgba8();
+        let width = self.image.width.max(1) as usize;
+        for (name, channel) in &channels {
+            let plane: Vec<Vec<u16>> = pixels
+                .chunks(width)
+                .map(|row| row.iter().map(|pixel| widen(pixel[*channel])).collect())
+                .collect();
+
+            writeln!(w, "{}", name)?;
+            for row in ramp_rows(&self.downscaled(&plane, opts), None, opts) {
+                writeln!(w, "{}", row)?;
+            }
+        }
+
+        Ok(channels.len())
+    }
+
+    /// The ASCII art, one string per row
+    fn ascii_rows(&self, opts: &RenderOptions) -> Vec<String> {
+        if self.fully_transparent() {
+            return vec![opts.transparent_placeholder.unwrap_or(' ').to_string()];
+        }
+
+        let grid = self.art_grid(opts);
+        let transparent = match (self.alpha(), opts.transparent_char) {
+            (Some(alpha), Some(_)) => Some(
+                self.scaled(alpha, opts)
+                    .into_iter()
+                    .map(|row| row.into_iter().map(|alpha| alpha == 0).collect())
+                    .collect::<Vec<Vec<bool>>>(),
+            ),
+            _ => None,
+        };
+        ramp_rows(&grid, transparent.as_deref(), opts)
+    }
+
+    /// The grid reduced to the output size, one value per character of the
+    /// art
+    fn art_grid(&self, opts: &RenderOptions) -> Vec<Vec<u16>> {
+        let mut grid = self.downscaled(self.grid(), opts);
+        if let Some(tile) = opts.adaptive_tile {
+            equalize_tiles(&mut grid, tile);
+        }
+        grid
+    }
+
+    /// Whether there is nothing but transparent pixels to render
+    fn fully_transparent(&self) -> bool {
+        self.alpha()
+            .is_some_and(|alpha| alpha.iter().flatten().all(|alpha| *alpha == 0))
+    }
+
+    /// A plane of grid values reduced to the output size, by block averaging
+    /// if enabled
+    fn downscaled(&self, plane: &[Vec<u16>], opts: &RenderOptions) -> Vec<Vec<u16>> {
+        match opts.block_average {
+            Some(rounding) => {
+                let (width, height) = self.dimensions();
+                let (cols, rows) = opts.dimensions(width, height);
+                average_image(plane, cols, rows, rounding)
+            }
+            None => self.scaled(plane, opts),
+        }
+    }
+
+    /// A per-pixel plane of the image resized to the output size
+    fn scaled<T: Copy>(&self, plane: &[Vec<T>], opts: &RenderOptions) -> Vec<Vec<T>> {
+        let (width, height) = self.dimensions();
+        let (cols, rows) = opts.dimensions(width, height);
+        resize_image(plane, cols, rows)
+    }
+
+    /// Width and height of the image in pixels
+    fn dimensions(&self) -> (usize, usize) {
+        (self.image.width as usize, self.image.height as usize)
+    }
 }
 
-enum Chunk<'a> {
-    IHDR(IHDRData),
-    PLTE(Vec<PLTEEntry>),
-    IDAT(&'a [u8]),
-    IEND,
-    BKGD(BKGD),
-    CHRM,
-    GAMA,
-    HIST,
-    PHYS,
-    SBIT,
-    TEXT,
-    TIME,
-    TRNS(&'a [u8]),
-    ZTXT,
+/// Samples of the viridis color scale at even steps from the lowest value to
+/// the highest
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 44, 122],
+    [59, 81, 139],
+    [44, 113, 142],
+    [33, 144, 141],
+    [39, 173, 129],
+    [92, 200, 99],
+    [170, 220, 50],
+    [253, 231, 37],
+];
+
+/// The color of a grid value on the viridis scale, interpolated between the
+/// two closest samples
+fn viridis(value: u16) -> [u8; 3] {
+    let position = value as f32 / 65535.0 * (VIRIDIS.len() - 1) as f32;
+    let low = (position as usize).min(VIRIDIS.len() - 2);
+    let t = position - low as f32;
+    let (from, to) = (VIRIDIS[low], VIRIDIS[low + 1]);
+    [0, 1, 2].map(|c| (from[c] as f32 + (to[c] as f32 - from[c] as f32) * t).round() as u8)
 }
 
-impl<'a> Chunk<'a> {
-    fn new(image: &'a mut ImageHelper) -> io::Result<Self> {
-        let len_slice = &image.data[image.offset..image.offset + 4];
-        let len = ((len_slice[0] as u32) << (8 * 3)
-            | (len_slice[1] as u32) << (8 * 2)
-            | (len_slice[2] as u32) << (8 * 1)
-            | (len_slice[3] as u32) << (8 * 0)) as usize;
-        image.offset += 4;
+/// One row of ASCII art as runs of a repeated character
+pub type RunLength = Vec<(char, usize)>;
 
-        // get type
-        image.offset += 4;
-        let data = &image.data[image.offset..image.offset + len];
-        let chunk = match String::from_utf8_lossy(&image.data[image.offset - 4..image.offset])
-            .to_string()
-            .as_str()
-        {
-            "IHDR" => Self::IHDR(IHDRData::from(data)?),
-            "PLTE" => {
-                if len % 3 != 0 {
-                    pngerr!("invalid PLTE chunk");
-                }
+/// 72 DPI in pixels per metre
+const DEFAULT_PPM: f32 = 2835.0;
 
-                let mut entries = Vec::new();
-                let mut idx = 0usize;
-                loop {
-                    if idx == len {
-                        break;
-                    }
+/// Writes one line of text, on a 24-bit ANSI `background` color if given
+fn write_line<W: Write>(w: &mut W, text: &str, background: Option<[u8; 3]>) -> io::Result<()> {
+    match background {
+        Some([r, g, b]) => writeln!(w, "\x1b[48;2;{};{};{}m{}\x1b[0m", r, g, b, text),
+        None => writeln!(w, "{}", text),
+    }
+}
 
-                    entries.push(PLTEEntry {
-                        _red: data[idx],
-                        _green: data[idx + 1],
-                        _blue: data[idx + 2],
-                    });
+/// Number of columns and rows of rendered art
+fn art_dimensions(rows: &[String]) -> (usize, usize) {
+    (
+        rows.first().map_or(0, |row| row.chars().count()),
+        rows.len(),
+    )
+}
 
-                    idx += 3;
-                }
-                Self::PLTE(entries)
+/// Renders every row of the scaled `grid`, see `ascii_row`
+fn ramp_rows(
+    grid: &[Vec<u16>],
+    transparent: Option<&[Vec<bool>]>,
+    opts: &RenderOptions,
+) -> Vec<String> {
+    (0..grid.len())
+        .map(|r| {
+            let above = &grid[r.saturating_sub(1)];
+            let below = &grid[(r + 1).min(grid.len() - 1)];
+            let transparent = transparent.map(|rows| &rows[r][..]);
+            ascii_row([above, &grid[r], below], transparent, opts)
+        })
+        .collect()
+}
+
+/// Stretches the values of each `tile`x`tile` block of the grid from the
+/// block's lowest value to its highest over the whole grid range. Blocks of a
+/// single value are left alone.
+fn equalize_tiles(grid: &mut [Vec<u16>], tile: usize) {
+    let tile = tile.max(1);
+    let (rows, cols) = (grid.len(), grid.first().map_or(0, |row| row.len()));
+    for top in (0..rows).step_by(tile) {
+        for left in (0..cols).step_by(tile) {
+            let (bottom, right) = ((top + tile).min(rows), (left + tile).min(cols));
+            let (mut low, mut high) = (u16::MAX, 0);
+            for value in grid[top..bottom].iter().flat_map(|row| &row[left..right]) {
+                low = low.min(*value);
+                high = high.max(*value);
             }
-            "IDAT" => Self::IDAT(data),
-            "IEND" => {
-                if len != 0 {
-                    pngerr!("IEND chunk must not contain any data");
-                }
-                Self::IEND
+            if low >= high {
+                continue;
             }
-            "bKGD" => match len {
-                1 => Self::BKGD(BKGD::PaletteIndex(data[0])),
-                2 => Self::BKGD(BKGD::Greyscale(u16::from_be_bytes([data[0], data[1]]))),
-                6 => Self::BKGD(BKGD::RGB(
-                    u16::from_be_bytes([data[0], data[1]]),
-                    u16::from_be_bytes([data[2], data[3]]),
-                    u16::from_be_bytes([data[4], data[5]]),
-                )),
-                _ => {
-                    pngerr!("invalid bKGD chunk");
+
+            let (low, range) = (low as u32, (high - low) as u32);
+            for row in &mut grid[top..bottom] {
+                for value in &mut row[left..right] {
+                    *value = ((*value as u32 - low) * 65535 / range) as u16;
                 }
-            },
-            "cHRM" => Self::CHRM,
-            "gAMA" => Self::GAMA,
-            "hIST" => Self::HIST,
-            "pHYs" => Self::PHYS,
-            "sBIT" => Self::SBIT,
-            "tEXt" => Self::TEXT,
-            "tIME" => Self::TIME,
-            "tRNS" => Self::TRNS(data),
-            "zTXT" => Self::ZTXT,
-            _ => {
-                pngerr!("{} is an invalid PNG chunk", String::from_utf8_lossy(data));
             }
-        };
-        image.offset += len;
+        }
+    }
+}
 
-        // get the CRC
-        // TODO: Can actually ignore this
-        let _crc = &image.data[image.offset..image.offset + 4];
-        image.offset += 4;
+/// Renders the middle of three consecutive rows of the scaled grid, the rows
+/// around it are needed for edge detection and repeat it at the borders.
+/// `transparent` marks the fully transparent pixels of the middle row.
+fn ascii_row(rows: [&[u16]; 3], transparent: Option<&[bool]>, opts: &RenderOptions) -> String {
+    let repeat = if opts.char_aspect_double { 2 } else { 1 };
+    let row: String = rows[1]
+        .iter()
+        .enumerate()
+        .flat_map(|(c, darkness)| {
+            let fill = opts
+                .transparent_char
+                .filter(|_| transparent.is_some_and(|transparent| transparent[c]));
+            let ch = fill
+                .or_else(|| {
+                    let edge = edge_glyph(rows, c, opts.edge_threshold?)?;
 
-        Ok(chunk)
+                    // diagonal edges lean the other way in the mirror image
+                    Some(match edge {
+                        '/' if opts.mirror => '\\',
+                        '\\' if opts.mirror => '/',
+                        edge => edge,
+                    })
+                })
+                .unwrap_or_else(|| {
+                    let darkness = match opts.brightness_window {
+                        Some(window) => windowed(*darkness, window),
+                        None => Some(*darkness),
+                    };
+                    let darkness = darkness.map(|darkness| posterized(darkness, opts.levels));
+                    match darkness {
+                        None => ' ',
+                        Some(darkness) if opts.coverage_ramp => coverage_glyph(darkness),
+                        Some(darkness) => glyph(darkness),
+                    }
+                });
+            let ch = if opts.full_width_cells {
+                full_width(ch)
+            } else {
+                ch
+            };
+            std::iter::repeat_n(ch, repeat)
+        })
+        .collect();
+
+    if opts.mirror {
+        row.chars().rev().collect()
+    } else {
+        row
     }
 }
 
-/// IHDR Chunk - RFC 2083 (section 4.1.1)
-pub struct IHDRData {
-    /// width in pixels
-    width: u32,
+/// Picks the line character closest to the orientation of the edge at column
+/// `c` of the middle row, if the Sobel gradient magnitude there exceeds
+/// `threshold`, which is on the 0-255 scale
+fn edge_glyph(rows: [&[u16]; 3], c: usize, threshold: u16) -> Option<char> {
+    let cols = rows[1].len();
+    let at = |dr: isize, dc: isize| -> f32 {
+        let x = (c as isize + dc).clamp(0, cols as isize - 1) as usize;
+        rows[(1 + dr) as usize][x] as f32 / 257.0
+    };
 
-    /// height in pixels
-    height: u32,
+    // the y axis points down
+    let gx = (at(-1, 1) + 2.0 * at(0, 1) + at(1, 1)) - (at(-1, -1) + 2.0 * at(0, -1) + at(1, -1));
+    let gy = (at(1, -1) + 2.0 * at(1, 0) + at(1, 1)) - (at(-1, -1) + 2.0 * at(-1, 0) + at(-1, 1));
+    let magnitude = (gx * gx + gy * gy).sqrt();
+    if magnitude <= threshold as f32 {
+        return None;
+    }
 
-    /// the number of bits per sample or per palette index (not per pixel)
-    bit_depth: u8,
+    // edges run perpendicular to the gradient
+    let angle = gy.atan2(gx).to_degrees().rem_euclid(180.0);
+    let edge = if !(22.5..157.5).contains(&angle) {
+        '|'
+    } else if angle < 67.5 {
+        '/'
+    } else if angle < 112.5 {
+        '-'
+    } else {
+        '\\'
+    };
+    Some(edge)
+}
 
-    /// the interpretation of the image data
-    color_type: ColorType,
+/// Maps a grid value to its ramp character
+fn glyph(darkness: u16) -> char {
+    RAMP.as_bytes()[ramp_index(darkness, RAMP.len())] as char
+}
 
-    /// a boolean value to determine if image is interlaced or not
-    /// the only available interlace type is "Adam7 interlace"
-    interlace_method: bool,
+/// Index into a ramp of `len` characters for a grid value, splitting the grid
+/// range into `len` equal steps
+fn ramp_index(darkness: u16, len: usize) -> usize {
+    let idx = ((len as u32 * darkness as u32) / 65535) as usize;
+    idx.min(len.saturating_sub(1))
 }
 
-impl IHDRData {
-    fn from(data: &[u8]) -> io::Result<Self> {
-        let idhr = Self {
-            width: (data[0] as u32) << (8 * 3)
-                | (data[1] as u32) << (8 * 2)
-                | (data[2] as u32) << (8 * 1)
-                | (data[3] as u32) << (8 * 0),
-            height: (data[4] as u32) << (8 * 3)
-                | (data[5] as u32) << (8 * 2)
-                | (data[6] as u32) << (8 * 1)
-                | (data[7] as u32) << (8 * 0),
-            bit_depth: data[8],
-            color_type: match data[9] {
-                0 => ColorType::Greyscale,
-                2 => ColorType::RGB,
-                3 => ColorType::PaletteIndex,
-                4 => ColorType::GreyscaleAlpha,
-                6 => ColorType::RGBA,
-                _ => {
-                    pngerr!("invalid color type");
-                }
-            },
-            // ignore filter and compression methods since there is only one
-            interlace_method: data[12] == 1,
-        };
+/// The character of `ramp`, ordered from the least to the most dense, for
+/// each 8-bit brightness, using the same steps as the rendered art so callers
+/// can map their own brightness data consistently. An empty ramp maps every
+/// brightness to a space.
+pub fn ramp_lut(ramp: &[char]) -> [char; 256] {
+    let mut lut = [' '; 256];
+    if ramp.is_empty() {
+        return lut;
+    }
 
-        Ok(idhr)
+    for (brightness, glyph) in lut.iter_mut().enumerate() {
+        *glyph = ramp[ramp_index(widen(brightness as u8), ramp.len())];
+    }
+    lut
+}
+
+/// Maps a grid value to the ramp character whose ink coverage is closest to
+/// it, rather than assuming the ramp is evenly spaced
+fn coverage_glyph(darkness: u16) -> char {
+    let target = darkness as u32 * 255 / 65535;
+    let idx = (0..RAMP_COVERAGE.len())
+        .min_by_key(|idx| RAMP_COVERAGE[*idx].abs_diff(target as u8))
+        .unwrap();
+    RAMP.as_bytes()[idx] as char
+}
+
+/// Stretches a grid value inside the 8-bit `low` to `high` window over the
+/// whole grid range, values outside the window have no glyph
+fn windowed(darkness: u16, (low, high): (u8, u8)) -> Option<u16> {
+    let (low, high) = (widen(low) as u32, widen(high) as u32);
+    let darkness = darkness as u32;
+    if darkness < low || darkness > high {
+        return None;
+    }
+    if low == high {
+        return Some(u16::MAX);
+    }
+
+    Some(((darkness - low) * 65535 / (high - low)) as u16)
+}
+
+/// Splits the grid range into `levels` equal bands and maps a grid value to
+/// the level of its band, the levels evenly spaced from the lowest value to
+/// the highest and a single level being mid-grey. 0 levels leave the value
+/// unchanged.
+fn posterized(darkness: u16, levels: u8) -> u16 {
+    match levels {
+        0 => darkness,
+        1 => u16::MAX / 2,
+        _ => {
+            let levels = levels as u32;
+            let band = darkness as u32 * levels / 65536;
+            (band * 65535 / (levels - 1)) as u16
+        }
+    }
+}
+
+/// The full-width form of a printable ASCII character, other characters are
+/// returned unchanged
+fn full_width(ch: char) -> char {
+    match ch {
+        ' ' => '\u{3000}',
+        '!'..='~' => char::from_u32(ch as u32 - 0x21 + 0xff01).unwrap_or(ch),
+        _ => ch,
+    }
+}
+
+/// Scales an 8-bit grid value to the full 16-bit range of the grid
+fn widen(value: u8) -> u16 {
+    value as u16 * 257
+}
+
+/// Quotes `text` as a JSON string
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encodes `data` as standard padded base64
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = (group[0] as u32) << 16
+            | (*group.get(1).unwrap_or(&0) as u32) << 8
+            | *group.get(2).unwrap_or(&0) as u32;
+        for idx in 0..4 {
+            if idx <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Options controlling how an `Img` is rendered
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// size of the rendered art in characters
+    pub size: Size,
+
+    /// append the ramp and the brightness each glyph represents below the art
+    pub with_legend: bool,
+
+    /// draw cells whose Sobel gradient magnitude exceeds this threshold with
+    /// `/`, `\`, `|`, or `-` following the edge instead of the ramp
+    pub edge_threshold: Option<u16>,
+
+    /// write the image's Title or Description text metadata below the art
+    pub caption_from_metadata: bool,
+
+    /// write every character twice side by side, roughly squaring the
+    /// aspect ratio of the art on terminals with tall character cells
+    pub char_aspect_double: bool,
+
+    /// character written for fully transparent pixels, which are rendered by
+    /// their brightness like any other pixel when not set
+    pub transparent_char: Option<char>,
+
+    /// the whole art of a fully transparent image, a single space by default.
+    /// It is never trimmed away.
+    pub transparent_placeholder: Option<char>,
+
+    /// height to width ratio of a terminal character cell, `Size::Height`
+    /// widens the art by this factor to keep the image's proportions. When
+    /// not set it is 2, close to most terminal fonts, or 1 with
+    /// `char_aspect_double`, which already corrects for tall cells.
+    pub cell_aspect: Option<f32>,
+
+    /// frame the art in a box-drawing border, which is not counted in the
+    /// returned dimensions
+    pub border: bool,
+
+    /// reverse the characters of every row, mirroring the art horizontally
+    pub mirror: bool,
+
+    /// only render grid values from the first to the second value, stretched
+    /// over the whole ramp, anything outside the window is a space
+    pub brightness_window: Option<(u8, u8)>,
+
+    /// downscale by averaging the pixels each character covers instead of
+    /// sampling one of them, rounding the average as given
+    pub block_average: Option<Rounding>,
+
+    /// write the full-width forms of the characters for terminals with CJK
+    /// fonts, each taking two cells so `Size::Height` halves the columns
+    pub full_width_cells: bool,
+
+    /// draw the image's bKGD color behind the art, as an ANSI background
+    /// color in text output and the page background in HTML
+    pub background_from_metadata: bool,
+
+    /// drop the spaces at the end of each row of text output, leaving blank
+    /// rows empty. Rows keep their spaces inside a border.
+    pub trim_trailing_spaces: bool,
+
+    /// pick the ramp character whose measured ink coverage best matches each
+    /// grid value instead of spacing the ramp evenly, see `RAMP_COVERAGE`
+    pub coverage_ramp: bool,
+
+    /// posterize the grid into this many evenly spaced bands before picking
+    /// characters, so at most this many ramp characters appear. 0 keeps
+    /// every level.
+    pub levels: u8,
+
+    /// split the art into tiles of this many characters in each direction and
+    /// stretch the grid values of each tile over the whole ramp, bringing out
+    /// detail in both dark and bright regions of the same image
+    pub adaptive_tile: Option<usize>,
+
+    /// leave out ANSI escape codes, so output written to a file or pipe is
+    /// plain text: the bKGD background is not drawn and heat maps fall back
+    /// to the ramp
+    pub no_ansi: bool,
+}
+
+/// How a block average is reduced to a grid value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    /// round down, which darkens slightly
+    Floor,
+
+    /// round to the nearest value
+    Nearest,
+}
+
+impl RenderOptions {
+    /// Number of columns and rows of the art for an image of `width`x`height`
+    /// pixels
+    pub fn dimensions(&self, width: usize, height: usize) -> (usize, usize) {
+        // doubled characters already make up for tall cells, and full-width
+        // characters span two cells
+        let default = if self.char_aspect_double { 1.0 } else { 2.0 };
+        let cell_aspect = self.cell_aspect.unwrap_or(default);
+        let cell_aspect = if self.full_width_cells {
+            cell_aspect / 2.0
+        } else {
+            cell_aspect
+        };
+        self.size.fit(width, height, cell_aspect)
+    }
+}
+
+/// Size of the rendered art in characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    /// number of rows, the number of columns maintains the aspect ratio
+    Height(usize),
+
+    /// exact number of columns and rows
+    Exact(usize, usize),
+}
+
+impl Default for Size {
+    fn default() -> Self {
+        Self::Height(150)
+    }
+}
+
+impl Size {
+    /// Number of columns and rows for an image of `width`x`height` pixels,
+    /// assuming square character cells
+    pub fn dimensions(&self, width: usize, height: usize) -> (usize, usize) {
+        self.fit(width, height, 1.0)
+    }
+
+    /// Like `dimensions` for character cells `cell_aspect` times taller than
+    /// they are wide
+    fn fit(&self, width: usize, height: usize, cell_aspect: f32) -> (usize, usize) {
+        match *self {
+            Self::Height(rows) => {
+                if height == 0 {
+                    return (0, 0);
+                }
+                let aspect_ratio = width as f32 / height as f32 * cell_aspect;
+                ((aspect_ratio * rows as f32) as usize, rows)
+            }
+            Self::Exact(cols, rows) => (cols, rows),
+        }
+    }
+}
+
+/// Writes the ramp with approximate brightness percentages marked underneath
+fn write_legend<W: Write>(w: &mut W) -> io::Result<()> {
+    let chars: Vec<char> = RAMP.chars().collect();
+    let last = chars.len() - 1;
+    let mut scale = vec![' '; chars.len()];
+    for percent in [0usize, 25, 50, 75, 100] {
+        let label = format!("{}%", percent);
+        let pos = (last * percent + 50) / 100;
+        let start = pos.min(chars.len() - label.len());
+        for (i, c) in label.chars().enumerate() {
+            scale[start + i] = c;
+        }
+    }
+
+    writeln!(w)?;
+    writeln!(w, "{}", chars.iter().collect::<String>())?;
+    writeln!(w, "{}", scale.iter().collect::<String>())
+}
+
+/// Resizes the grid by averaging the block of values each output cell covers
+fn average_image(
+    grid: &[Vec<u16>],
+    target_width: usize,
+    target_height: usize,
+    rounding: Rounding,
+) -> Vec<Vec<u16>> {
+    let height = grid.len();
+    let width = grid.first().map_or(0, |row| row.len());
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+
+    // blocks cover at least one value when upscaling
+    let span = |cell: usize, len: usize, target: usize| {
+        let start = cell * len / target;
+        start..((cell + 1) * len / target).max(start + 1)
+    };
+    (0..target_height)
+        .map(|r| {
+            let rows = span(r, height, target_height);
+            (0..target_width)
+                .map(|c| {
+                    let cols = span(c, width, target_width);
+                    let count = (rows.len() * cols.len()) as u64;
+                    let sum: u64 = grid[rows.clone()]
+                        .iter()
+                        .flat_map(|row| &row[cols.clone()])
+                        .map(|value| *value as u64)
+                        .sum();
+                    match rounding {
+                        Rounding::Floor => (sum / count) as u16,
+                        Rounding::Nearest => ((sum + count / 2) / count) as u16,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn resize_image<T: Copy>(
+    grid: &[Vec<T>],
+    target_width: usize,
+    target_height: usize,
+) -> Vec<Vec<T>> {
+    let height = grid.len();
+    let width = grid.first().map_or(0, |row| row.len());
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+
+    let vertical_skip = height as f32 / target_height as f32;
+    let horizontal_skip = width as f32 / target_width as f32;
+    let mut resized = Vec::new();
+    for r in 0..target_height {
+        let y = (r as f32 * vertical_skip) as usize;
+        let mut row = Vec::new();
+        for c in 0..target_width {
+            let x = (c as f32 * horizontal_skip) as usize;
+            row.push(grid[y][x]);
+        }
+        resized.push(row);
+    }
+    resized
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    /// width in pixels
+    width: u32,
+
+    /// height in pixels
+    height: u32,
+
+    /// the number of bits per sample or per palette index (not per pixel)
+    bit_depth: u8,
+
+    /// the interpretation of the image data
+    color_type: ColorType,
+
+    /// a boolean value to determine if image is interlaced or not
+    /// the only available interlace type is "Adam7 interlace"
+    interlaced: bool,
+
+    /// actual image data
+    data: Vec<u8>,
+
+    /// palettes (PLTE chunk)
+    plte: Option<Vec<PLTEEntry>>,
+
+    /// background color (bKGD chunk)
+    background: Option<Bkgd>,
+
+    /// simple transparency (tRNS chunk)
+    transparancy: Option<Transparancy>,
+
+    /// physical pixel dimensions (pHYs chunk)
+    physical: Option<PHYSData>,
+
+    /// EXIF orientation tag (eXIf chunk)
+    orientation: Option<u16>,
+
+    /// suggested palettes (sPLT chunks)
+    suggested_palettes: Vec<SuggestedPalette>,
+
+    /// textual metadata in file order (tEXt, zTXt, and iTXt chunks)
+    text: Vec<TextEntry>,
+
+    /// number of filter reconstructions clamped in saturating mode
+    filter_clamps: usize,
+
+    /// whether filtering the reconstructed scanlines again reproduced the
+    /// decompressed image data, when decoded with `verify_filters`
+    filters_roundtrip: Option<bool>,
+}
+
+/// ITU-R BT.601 luma of an 8-bit RGB color
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((299 * r as u32 + 587 * g as u32 + 114 * b as u32 + 500) / 1000) as u8
+}
+
+/// upper bound of `Image::unique_color_count`
+pub const MAX_UNIQUE_COLORS: usize = 1 << 16;
+
+/// Options controlling how strictly a PNG is decoded
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// recover from spec violations that buggy exporters are known to produce
+    /// instead of failing
+    pub lenient: bool,
+
+    /// color used for palette indices that have no PLTE entry in lenient mode,
+    /// the last palette entry is used when not set
+    pub palette_fallback: Option<[u8; 3]>,
+
+    /// rotate and flip the image upright according to its EXIF orientation
+    pub auto_orient: bool,
+
+    /// look for the PNG signature within the first few KB of the file instead
+    /// of requiring it at the very start, rescuing files with junk prepended
+    pub scan_for_signature: bool,
+
+    /// map all 16 bits of 16-bit greyscale images onto the ramp when building
+    /// an `Img`, instead of truncating them to 8 bits first
+    pub precise_16bit: bool,
+
+    /// blend images with an alpha channel or tRNS transparency over this
+    /// color, leaving an opaque greyscale, RGB, or palette image. 16-bit
+    /// images are blended before they are reduced to 8 bits.
+    pub composite_background: Option<[u8; 3]>,
+
+    /// clamp the filter reconstruction arithmetic instead of wrapping it,
+    /// counting the clamps in `DecodeStats`. This is a debugging aid for
+    /// corrupt streams, whose garbage bytes overshoot their predictions: an
+    /// image decoded without clamps is identical to its normal decode. The
+    /// spec does allow encoders to rely on wrapping, so a few clamps in a
+    /// valid image are possible and change its pixels.
+    pub saturating_filters: bool,
+
+    /// the preset dictionary for image data compressed with one, which a few
+    /// nonstandard encoders use. Such image data can not be decoded without it.
+    pub zlib_dictionary: Option<Vec<u8>>,
+
+    /// treat the color of images with an alpha channel as already multiplied
+    /// by the alpha, as some tools wrongly write it, and divide it back out
+    /// before anything else uses the pixels. Fully transparent pixels become
+    /// black.
+    pub assume_premultiplied: bool,
+
+    /// most chunks a file may have before decoding fails, guarding against
+    /// files of countless tiny chunks. `DEFAULT_MAX_CHUNKS` when not set.
+    pub max_chunks: Option<usize>,
+
+    /// how an `Img` reduces the color of each pixel to its brightness
+    pub luma: LumaMethod,
+
+    /// filter the reconstructed scanlines again to check them against the
+    /// decompressed image data, see `Image::verify_roundtrip`. This costs a
+    /// second pass over the pixels.
+    pub verify_filters: bool,
+}
+
+impl DecodeOptions {
+    /// Options that blend transparent pixels over the background of `target`,
+    /// so anti-aliased edges fade into what surrounds the art
+    pub fn for_target(target: RenderTarget) -> Self {
+        Self {
+            composite_background: Some(target.background()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Where the art is going to be displayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderTarget {
+    /// text output for a terminal, assumed to have a dark background
+    Terminal,
+
+    /// an HTML page, which has a light background
+    Html,
+}
+
+impl RenderTarget {
+    /// The color behind the art on this target
+    pub fn background(&self) -> [u8; 3] {
+        match self {
+            Self::Terminal => [0, 0, 0],
+            Self::Html => [255, 255, 255],
+        }
+    }
+}
+
+/// Formula reducing a color to its brightness
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LumaMethod {
+    /// the plain average of the channels, which makes blues look too bright
+    /// and reds too dark. Alpha counts as a channel.
+    Average,
+
+    /// ITU-R BT.601 luma, weighting each channel by how bright it appears
+    #[default]
+    Rec601,
+
+    /// ITU-R BT.709 luma, the weights used by HDTV and sRGB
+    Rec709,
+}
+
+impl LumaMethod {
+    /// Brightness of an 8-bit RGB color
+    pub fn luma(&self, r: u8, g: u8, b: u8) -> u8 {
+        let (wide_r, wide_g, wide_b) = (r as u32, g as u32, b as u32);
+        match self {
+            Self::Average => ((wide_r + wide_g + wide_b) / 3) as u8,
+            Self::Rec601 => luma(r, g, b),
+            Self::Rec709 => ((2126 * wide_r + 7152 * wide_g + 722 * wide_b + 5000) / 10000) as u8,
+        }
+    }
+}
+
+/// A spec violation that was recovered from while decoding leniently
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// type of the chunk the violation was found in
+    pub chunk: Option<String>,
+
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Reports a recoverable spec violation, which is an error unless decoding
+/// leniently in which case it is recorded in `diagnostics`
+fn violation(
+    lenient: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+    chunk: Option<&str>,
+    message: String,
+) -> io::Result<()> {
+    if !lenient {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+
+    diagnostics.push(Diagnostic {
+        chunk: chunk.map(str::to_owned),
+        message,
+    });
+    Ok(())
+}
+
+impl Image {
+    pub fn from(file: &str) -> io::Result<Self> {
+        Self::from_with_options(file, &DecodeOptions::default())
+    }
+
+    pub fn from_with_options(file: &str, opts: &DecodeOptions) -> io::Result<Self> {
+        Self::decode(file, opts, &mut Vec::new())
+    }
+
+    /// Decodes the file, also returning its contents untouched so callers can
+    /// rewrite its chunks without reading it again
+    pub fn from_retaining_bytes(file: &str) -> io::Result<(Self, Vec<u8>)> {
+        let opts = DecodeOptions::default();
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from(file, &opts)?;
+        let mut image = Self::decode_chunks(&mut chunks, &opts, diagnostics, &mut |_| {})?;
+        image.finish(&opts, diagnostics)?;
+        Ok((image, chunks.data))
+    }
+
+    /// Decodes the file along with statistics about the decoding
+    pub fn from_with_stats(file: &str, opts: &DecodeOptions) -> io::Result<(Self, DecodeStats)> {
+        let image = Self::from_with_options(file, opts)?;
+        let stats = DecodeStats {
+            filter_clamps: image.filter_clamps,
+        };
+        Ok((image, stats))
+    }
+
+    /// Decodes leniently, returning the best-effort image along with every
+    /// spec violation that was recovered from. Problems that leave nothing to
+    /// decode are still errors.
+    pub fn from_lenient(file: &str) -> io::Result<(Self, Vec<Diagnostic>)> {
+        let opts = DecodeOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let mut diagnostics = Vec::new();
+        let image = Self::decode(file, &opts, &mut diagnostics)?;
+        Ok((image, diagnostics))
+    }
+
+    /// Decodes only the first Adam7 pass of an interlaced image, a 1/8 scale
+    /// subimage stored at the start of the image data, and scales it back up to
+    /// the full dimensions with each pixel filling its 8x8 block. Images that
+    /// are not interlaced are decoded in full.
+    pub fn from_interlaced_preview(file: &str) -> io::Result<Self> {
+        let opts = DecodeOptions::default();
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from(file, &opts)?;
+        let (mut image, compressed_data) = Self::read_chunks(&mut chunks, &opts, diagnostics)?;
+        let mut decoder = ZlibDecoder::new(&compressed_data[..]);
+        if !image.interlaced {
+            let mut filtered = Vec::new();
+            decoder.read_to_end(&mut filtered)?;
+            reverse_filter(filtered, &mut image, &opts, diagnostics, &mut |_| {})?;
+            image.finish(&opts, diagnostics)?;
+            return Ok(image);
+        }
+
+        let (width, height) = (image.width, image.height);
+        let pass_width = width.div_ceil(8);
+        let pass_height = height.div_ceil(8);
+        let stride = image.pixel_format().stride(pass_width as usize) + 1;
+
+        // the first pass is all that needs decompressing
+        let pass_len = stride * pass_height as usize;
+        let mut filtered = Vec::new();
+        decoder.take(pass_len as u64).read_to_end(&mut filtered)?;
+        if filtered.len() < pass_len {
+            pngerr!("image data is too short for the first Adam7 pass");
+        }
+
+        let bpp = image.pixel_format().filter_bpp();
+        unfilter(
+            &filtered,
+            stride,
+            pass_height as usize,
+            bpp,
+            false,
+            &mut image.data,
+        )?;
+        image.width = pass_width;
+        image.height = pass_height;
+        image.interlaced = false;
+        image.finish(&opts, diagnostics)?;
+        image.remap(width, height, |x, y| (x / 8, y / 8));
+
+        Ok(image)
+    }
+
+    /// The palette of the indexed PNG at `file`, read from the chunks up to
+    /// the PLTE chunk without decompressing any image data. `None` if the
+    /// image is not palette based or can not be read.
+    pub fn palette_preview(file: &str) -> Option<Vec<[u8; 3]>> {
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from(file, &DecodeOptions::default()).ok()?;
+        match chunks.next(diagnostics).ok()?? {
+            Chunk::Ihdr(header) if header.color_type == ColorType::PaletteIndex => {}
+            _ => return None,
+        }
+
+        // the PLTE chunk has to come before the image data
+        loop {
+            match chunks.next(diagnostics).ok()?? {
+                Chunk::Plte(plte) => {
+                    let colors = plte
+                        .iter()
+                        .map(|entry| [entry._red, entry._green, entry._blue])
+                        .collect();
+                    return Some(colors);
+                }
+                Chunk::Idat(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Decompresses and unfilters the PNG in `bytes` into a scratch buffer
+    /// that is reused between calls, then throws the pixels away. This
+    /// measures decoding without the cost of allocating the output.
+    pub fn decode_and_discard(bytes: &[u8]) -> io::Result<()> {
+        thread_local! {
+            static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+
+        SCRATCH.with(|scratch| Self::decode_into(bytes, &mut scratch.borrow_mut()))?;
+        Ok(())
+    }
+
+    /// Decompresses and unfilters the PNG in `bytes` into `buf`, replacing
+    /// its contents while keeping its capacity so that decoding many images
+    /// of the same size allocates only once. `buf` holds the scanlines
+    /// without their filter type bytes, the passes of interlaced images put
+    /// back together, at the bit depth the file stores them in. The returned
+    /// width, height, and format describe their layout.
+    pub fn decode_into(bytes: &[u8], buf: &mut Vec<u8>) -> io::Result<(u32, u32, PixelFormat)> {
+        let opts = DecodeOptions::default();
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from_bytes(bytes.to_vec(), &opts)?;
+        let (mut image, compressed_data) = Self::read_chunks(&mut chunks, &opts, diagnostics)?;
+        let mut filtered = Vec::new();
+        ZlibDecoder::new(&compressed_data[..]).read_to_end(&mut filtered)?;
+
+        buf.clear();
+        image.data = std::mem::take(buf);
+        let result = reverse_filter(filtered, &mut image, &opts, diagnostics, &mut |_| {});
+        *buf = std::mem::take(&mut image.data);
+        result?;
+
+        Ok((image.width, image.height, image.pixel_format()))
+    }
+
+    /// Estimated memory in bytes needed for the decoded pixel data of an image
+    /// with `header`, plus the grid of an `Img` built from it
+    pub fn estimated_bytes(header: &IHDRData) -> u64 {
+        let pixels = header.width as u64 * header.height as u64;
+
+        // samples of every bit depth end up a byte each, 16-bit ones reduced
+        // to 8 bits and lower depths unpacked
+        let data = pixels * header.color_type.channels() as u64;
+
+        // 16 bits of brightness per pixel, and 16 bits of alpha for images
+        // with an alpha channel
+        let grid = match header.color_type {
+            ColorType::GreyscaleAlpha | ColorType::RGBA => pixels * 4,
+            _ => pixels * 2,
+        };
+
+        data + grid
+    }
+
+    fn decode(
+        file: &str,
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<Self> {
+        let mut image = Self::decode_samples(file, opts, diagnostics)?;
+        image.finish(opts, diagnostics)?;
+
+        Ok(image)
+    }
+
+    /// Decodes the image data and orients it, leaving the samples at their
+    /// original bit depth for `finish`
+    fn decode_samples(
+        file: &str,
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<Self> {
+        let mut chunks = ImageHelper::from(file, opts)?;
+        Self::decode_chunks(&mut chunks, opts, diagnostics, &mut |_| {})
+    }
+
+    /// `decode_samples` for a file that has already been read, `progress` is
+    /// called with the percentage decoded so far: inflating the image data
+    /// accounts for the first half and unfiltering for the second
+    fn decode_chunks(
+        chunks: &mut ImageHelper,
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+        progress: &mut dyn FnMut(u8),
+    ) -> io::Result<Self> {
+        let (image, compressed_data) = Self::read_chunks(chunks, opts, diagnostics)?;
+        image.decode_image_data(&compressed_data, opts, diagnostics, progress)
+    }
+
+    /// Inflates and unfilters `compressed_data`, the concatenated IDAT chunks
+    /// of the image whose other chunks have been applied, and orients it
+    fn decode_image_data(
+        mut self,
+        compressed_data: &[u8],
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+        progress: &mut dyn FnMut(u8),
+    ) -> io::Result<Self> {
+        // RFC 1950: the FDICT flag marks streams compressed with a preset
+        // dictionary
+        let needs_dictionary = compressed_data
+            .get(1)
+            .is_some_and(|flags| flags & 0x20 != 0);
+        let mut filtered = Vec::new();
+        progress(0);
+        if needs_dictionary {
+            filtered = inflate_with_dictionary(compressed_data, opts.zlib_dictionary.as_deref())?;
+            progress(50);
+        } else {
+            // decompress data a megabyte at a time to report progress
+            let mut decoder = ZlibDecoder::new(compressed_data);
+            while (&mut decoder).take(1 << 20).read_to_end(&mut filtered)? > 0 {
+                let inflated = decoder.total_in() as f32 / compressed_data.len().max(1) as f32;
+                progress((inflated.min(1.0) * 50.0) as u8);
+            }
+        }
+
+        reverse_filter(filtered, &mut self, opts, diagnostics, &mut |done| {
+            progress(50 + (done * 50.0) as u8)
+        })?;
+
+        if opts.auto_orient {
+            self.apply_orientation();
+        }
+
+        Ok(self)
+    }
+
+    /// Reads and validates every chunk, returning the image without any pixel
+    /// data along with the concatenated IDAT chunks
+    fn read_chunks(
+        chunks: &mut ImageHelper,
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<(Self, Vec<u8>)> {
+        let mut image = Self::empty();
+        let mut compressed_data: Vec<u8> = Vec::new();
+        let (mut seen_ihdr, mut seen_idat) = (false, false);
+
+        while let Some(chunk) = chunks.next(diagnostics)? {
+            check_ihdr_order(matches!(chunk, Chunk::Ihdr(_)), &mut seen_ihdr)?;
+            if let Chunk::Idat(data) = chunk {
+                compressed_data.extend(data);
+                seen_idat = true;
+                continue;
+            }
+
+            image.apply_chunk(
+                chunk,
+                !compressed_data.is_empty(),
+                opts.lenient,
+                diagnostics,
+            )?;
+        }
+
+        if !seen_ihdr {
+            pngerr!("PNG has no IHDR chunk");
+        }
+        if !seen_idat {
+            pngerr!("PNG has no IDAT chunk");
+        }
+        image.validate(opts.lenient, diagnostics)?;
+
+        Ok((image, compressed_data))
+    }
+
+    /// An image with no chunks applied yet
+    fn empty() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            bit_depth: 0,
+            color_type: ColorType::Greyscale,
+            interlaced: false,
+            data: Vec::new(),
+            plte: None,
+            background: None,
+            transparancy: None,
+            physical: None,
+            orientation: None,
+            suggested_palettes: Vec::new(),
+            text: Vec::new(),
+            filter_clamps: 0,
+            filters_roundtrip: None,
+        }
+    }
+
+    /// Applies a chunk other than IDAT and IEND to the image, `after_idat`
+    /// tells whether any image data preceeded it
+    fn apply_chunk(
+        &mut self,
+        chunk: Chunk,
+        after_idat: bool,
+        lenient: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<()> {
+        match chunk {
+            Chunk::Idat(_) | Chunk::Iend => {
+                // image data and the end of the image are handled by the caller
+            }
+            Chunk::Ihdr(ihdr) => {
+                self.width = ihdr.width;
+                self.height = ihdr.height;
+                self.bit_depth = ihdr.bit_depth;
+                self.color_type = ihdr.color_type;
+                self.interlaced = ihdr.interlace_method;
+            }
+            Chunk::Plte(plte) => {
+                // 4.1.2 - There must not be more than one PLTE chunk.
+                if self.plte.is_some() {
+                    let message = "PNG must not have more than one PLTE chunk".to_owned();
+                    violation(lenient, diagnostics, Some("PLTE"), message)?;
+                    return Ok(());
+                }
+
+                if self.background.is_some() {
+                    let message = "bKGD chunk can not preceed a PLTE chunk".to_owned();
+                    violation(lenient, diagnostics, Some("bKGD"), message)?;
+                }
+
+                self.plte = Some(plte);
+            }
+            Chunk::Bkgd(background) => {
+                if after_idat {
+                    let message = "bKGD chunk can not come after the IDAT chunk".to_owned();
+                    violation(lenient, diagnostics, Some("bKGD"), message)?;
+                }
+
+                let mismatch = match self.color_type {
+                    ColorType::PaletteIndex => {
+                        if let Bkgd::PaletteIndex(_) = background {
+                            None
+                        } else {
+                            Some("PNG with color type 3 can only have palette index bKGD chunk")
+                        }
+                    }
+                    ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                        if let Bkgd::Greyscale(_) = background {
+                            None
+                        } else {
+                            Some("PNG with color type 0 or 4 can only have grey bKGD chunk")
+                        }
+                    }
+                    ColorType::RGB | ColorType::RGBA => {
+                        if let Bkgd::Rgb(_, _, _) = background {
+                            None
+                        } else {
+                            Some("PNG with color type 2 or 6 can only have RGB bKGD chunk")
+                        }
+                    }
+                };
+                if let Some(message) = mismatch {
+                    violation(lenient, diagnostics, Some("bKGD"), message.to_owned())?;
+                    return Ok(());
+                }
+
+                self.background = Some(background);
+            }
+            Chunk::Chrm
+            | Chunk::Gama
+            | Chunk::Hist
+            | Chunk::Sbit
+            | Chunk::Time
+            | Chunk::Unknown => {
+                // ignore - not important in our use-case
+            }
+            Chunk::Splt(palette) => {
+                if after_idat {
+                    let message = "sPLT chunk can not come after the IDAT chunk".to_owned();
+                    violation(lenient, diagnostics, Some("sPLT"), message)?;
+                }
+
+                if self
+                    .suggested_palettes
+                    .iter()
+                    .any(|p| p.name == palette.name)
+                {
+                    let message = format!("more than one sPLT chunk is named {}", palette.name);
+                    violation(lenient, diagnostics, Some("sPLT"), message)?;
+                    return Ok(());
+                }
+
+                self.suggested_palettes.push(palette);
+            }
+            Chunk::Text(entry) => {
+                self.text.push(entry);
+            }
+            Chunk::Exif(orientation) => {
+                self.orientation = orientation;
+            }
+            Chunk::Phys(physical) => {
+                if after_idat {
+                    let message = "pHYs chunk can not come after the IDAT chunk".to_owned();
+                    violation(lenient, diagnostics, Some("pHYs"), message)?;
+                }
+
+                self.physical = Some(physical);
+            }
+            Chunk::Trns(data) => match self.color_type {
+                ColorType::PaletteIndex => {
+                    let plte_len = match &self.plte {
+                        Some(plte) => plte.len(),
+                        None => {
+                            let message = "tRNS chunk must come after the PLTE chunk".to_owned();
+                            violation(lenient, diagnostics, Some("tRNS"), message)?;
+                            data.len()
+                        }
+                    };
+                    let data = if lenient && data.len() > plte_len {
+                        let message = "tRNS chunk has more entries than PLTE chunk".to_owned();
+                        violation(lenient, diagnostics, Some("tRNS"), message)?;
+                        &data[..plte_len]
+                    } else {
+                        data
+                    };
+                    self.transparancy = Some(Transparancy::for_indexed_color(data, plte_len)?)
+                }
+                ColorType::Greyscale => {
+                    self.transparancy = Some(Transparancy::for_grayscale(data)?)
+                }
+                ColorType::RGB => self.transparancy = Some(Transparancy::for_rgb(data)?),
+                ColorType::GreyscaleAlpha | ColorType::RGBA => {
+                    let message =
+                        "PNG with color types 4 or 6 can not have a tRNS chunk".to_owned();
+                    violation(lenient, diagnostics, Some("tRNS"), message)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Validates the chunks against the color type and bit depth once they
+    /// have all been read
+    fn validate(&mut self, lenient: bool, diagnostics: &mut Vec<Diagnostic>) -> io::Result<()> {
+        // validate bit depth
+        let format = PixelFormat::new(self.color_type, self.bit_depth)?;
+
+        // 4.1.1 - Width and height give the image dimensions in pixels, zero
+        // is an invalid value
+        if self.width == 0 || self.height == 0 {
+            pngerr!(
+                "image dimensions must not be zero, IHDR gives {}x{}",
+                self.width,
+                self.height
+            );
+        }
+
+        // 4.1.2
+        // This chunk must appear for color type 3, and can appear for
+        // color types 2 and 6; it must not appear for color types 0 and
+        // 4. If this chunk does appear, it must precede the first IDAT
+        // chunk.
+        match format.color_type() {
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                // validate PLTE chunk existance, some exporters add a
+                // greyscale ramp that is safe to drop
+                if self.plte.is_some() {
+                    let message = format!(
+                        "PNG of {} color type cannot have a PLTE chunk",
+                        self.color_type
+                    );
+                    violation(lenient, diagnostics, Some("PLTE"), message)?;
+                    self.plte = None;
+                }
+            }
+            ColorType::PaletteIndex => {
+                // validate PLTE chunk existance
+                if self.plte.is_none() {
+                    pngerr!(
+                        "PNG of {} color type must have a PLTE chunk",
+                        self.color_type
+                    );
+                }
+
+                // validate palette entry length
+                let bit_depth_range = 2usize.pow(format.bit_depth() as u32);
+                if self.plte.as_ref().unwrap().len() > bit_depth_range {
+                    let message = format!(
+                        "PNG of {} color type can not have more entries that its bit depth range",
+                        self.color_type
+                    );
+                    violation(lenient, diagnostics, Some("PLTE"), message)?;
+                }
+            }
+            ColorType::RGB | ColorType::RGBA => {}
+        }
+
+        // 11.3.2.1, 11.3.5.1 - tRNS and bKGD samples can not exceed the
+        // largest value of the bit depth, palette indices are checked against
+        // the palette instead
+        let max = ((1u32 << self.bit_depth) - 1) as u16;
+        let in_range = |samples: &[u16]| samples.iter().all(|sample| *sample <= max);
+        let transparancy_in_range = match &self.transparancy {
+            Some(Transparancy::Greyscale(grey)) => in_range(&[*grey]),
+            Some(Transparancy::Rgb(red, green, blue)) => in_range(&[*red, *green, *blue]),
+            _ => true,
+        };
+        if !transparancy_in_range {
+            let message = format!(
+                "tRNS chunk has a sample larger than {}, the largest {}-bit value",
+                max, self.bit_depth
+            );
+            violation(lenient, diagnostics, Some("tRNS"), message)?;
+            self.transparancy = None;
+        }
+        let background_in_range = match &self.background {
+            Some(Bkgd::Greyscale(grey)) => in_range(&[*grey]),
+            Some(Bkgd::Rgb(red, green, blue)) => in_range(&[*red, *green, *blue]),
+            _ => true,
+        };
+        if !background_in_range {
+            let message = format!(
+                "bKGD chunk has a sample larger than {}, the largest {}-bit value",
+                max, self.bit_depth
+            );
+            violation(lenient, diagnostics, Some("bKGD"), message)?;
+            self.background = None;
+        }
+
+        Ok(())
+    }
+
+    /// Post-processing of the reconstructed pixel data
+    fn finish(
+        &mut self,
+        opts: &DecodeOptions,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<()> {
+        if opts.assume_premultiplied {
+            unpremultiply(self);
+        }
+        unpack_samples(self);
+        // a 16-bit color key has to be matched before the low bytes go, and
+        // keyed pixels are blended like any other transparent ones
+        if self.bit_depth == 16 || opts.composite_background.is_some() {
+            expand_color_key(self);
+        }
+        if let Some(background) = opts.composite_background {
+            composite(self, background);
+        }
+        convert_bit_depth(self);
+
+        if let ColorType::PaletteIndex = self.color_type {
+            check_palette_indices(self, opts, diagnostics)?;
+        }
+
+        Ok(())
+    }
+
+    /// Palettes suggested by the encoder for displays that can only show a
+    /// limited number of colors (sPLT chunks)
+    pub fn suggested_palettes(&self) -> &[SuggestedPalette] {
+        &self.suggested_palettes
+    }
+
+    /// The color type and bit depth of the pixel data, which changes as the
+    /// pixel data is converted after decoding
+    pub fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::new(self.color_type, self.bit_depth)
+            .expect("the color type and bit depth are validated before decoding")
+    }
+
+    /// Textual metadata in file order (tEXt, zTXt, and iTXt chunks)
+    pub fn text(&self) -> &[TextEntry] {
+        &self.text
+    }
+
+    /// EXIF orientation of the image (1-8), if it has an eXIf chunk carrying one
+    pub fn orientation(&self) -> Option<u16> {
+        self.orientation
+    }
+
+    /// Rotates and flips the image so it is upright according to its EXIF
+    /// orientation
+    pub fn apply_orientation(&mut self) {
+        let (w, h) = (self.width as usize, self.height as usize);
+        match self.orientation {
+            Some(2) => self.flip_horizontal(),
+            Some(3) => self.rotate_180(),
+            Some(4) => self.flip_vertical(),
+            Some(5) => self.transpose(|x, y| (y, x)),
+            Some(6) => self.rotate_90(),
+            Some(7) => self.transpose(|x, y| (w - 1 - y, h - 1 - x)),
+            Some(8) => self.rotate_270(),
+            _ => return,
+        }
+        self.orientation = Some(1);
+    }
+
+    /// Rotates the image 90 degrees clockwise
+    pub fn rotate_90(&mut self) {
+        let h = self.height as usize;
+        self.transpose(|x, y| (y, h - 1 - x));
+    }
+
+    /// Rotates the image 180 degrees
+    pub fn rotate_180(&mut self) {
+        let (w, h) = (self.width as usize, self.height as usize);
+        self.remap(self.width, self.height, |x, y| (w - 1 - x, h - 1 - y));
+    }
+
+    /// Rotates the image 270 degrees clockwise
+    pub fn rotate_270(&mut self) {
+        let w = self.width as usize;
+        self.transpose(|x, y| (w - 1 - y, x));
+    }
+
+    /// Mirrors the image left to right
+    pub fn flip_horizontal(&mut self) {
+        let w = self.width as usize;
+        self.remap(self.width, self.height, |x, y| (w - 1 - x, y));
+    }
+
+    /// Mirrors the image top to bottom
+    pub fn flip_vertical(&mut self) {
+        let h = self.height as usize;
+        self.remap(self.width, self.height, |x, y| (x, h - 1 - y));
+    }
+
+    /// Remaps the pixels into an image with swapped dimensions
+    fn transpose<F: Fn(usize, usize) -> (usize, usize)>(&mut self, source: F) {
+        self.remap(self.height, self.width, source);
+        if let Some(physical) = self.physical.as_mut() {
+            std::mem::swap(&mut physical.x_ppu, &mut physical.y_ppu);
+        }
+    }
+
+    /// Rebuilds the pixel data as a `width`x`height` image where the pixel at
+    /// `(x, y)` comes from `source(x, y)` in the current data
+    fn remap<F: Fn(usize, usize) -> (usize, usize)>(&mut self, width: u32, height: u32, source: F) {
+        let bits = self.pixel_format().bits_per_pixel();
+        let src_stride = (self.width as usize * bits).div_ceil(8);
+        let dst_stride = (width as usize * bits).div_ceil(8);
+        let mut data = vec![0u8; dst_stride * height as usize];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let (sx, sy) = source(x, y);
+                let src = &self.data[sy * src_stride..];
+                copy_pixel(src, sx, &mut data[y * dst_stride..], x, bits);
+            }
+        }
+
+        self.data = data;
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Writes the image as a Kitty graphics protocol escape sequence carrying
+    /// its RGBA pixels, so supporting terminals display the actual image
+    pub fn render_kitty<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let pixels: Vec<u8> = self.to_rgba8().into_iter().flatten().collect();
+        let payload = base64(&pixels);
+
+        // the payload is sent in chunks of at most 4096 bytes, m=1 marks that
+        // more chunks follow
+        let chunks: Vec<&[u8]> = payload.as_bytes().chunks(4096).collect();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let more = (idx + 1 < chunks.len()) as u8;
+            if idx == 0 {
+                write!(
+                    w,
+                    "\x1b_Ga=T,f=32,s={},v={},m={};",
+                    self.width, self.height, more
+                )?;
+            } else {
+                write!(w, "\x1b_Gm={};", more)?;
+            }
+            w.write_all(chunk)?;
+            write!(w, "\x1b\\")?;
+        }
+        writeln!(w)
+    }
+
+    /// Expands every pixel into 8-bit RGBA, applying the palette and any tRNS
+    /// transparency
+    pub fn to_rgba8(&self) -> Vec<[u8; 4]> {
+        (0..self.width as usize * self.height as usize)
+            .map(|idx| self.rgba_at(idx))
+            .collect()
+    }
+
+    /// The palette index of every pixel in row-major order, unpacked from bit
+    /// depths lower than 8, or `None` if the image is not palette based
+    pub fn palette_indices(&self) -> Option<Vec<u8>> {
+        if self.color_type != ColorType::PaletteIndex {
+            return None;
+        }
+
+        Some(self.unpacked_samples())
+    }
+
+    /// One sample per pixel of a single channel image, unpacking samples of
+    /// bit depths lower than 8 from the most significant bit of each byte
+    fn unpacked_samples(&self) -> Vec<u8> {
+        let bits = self.bit_depth as usize;
+        if bits >= 8 {
+            return self.data.clone();
+        }
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        let stride = (width * bits).div_ceil(8);
+        let mut samples = Vec::with_capacity(width * height);
+        for row in self.data.chunks(stride).take(height) {
+            unpack_row(row, width, bits, &mut samples);
+        }
+        samples
+    }
+
+    /// RGBA value of the pixel at `x` and `y`, applying the palette and any tRNS
+    /// transparency, or `None` when the coordinate is outside the image
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        Some(self.rgba_at(y as usize * self.width as usize + x as usize))
+    }
+
+    /// The smallest rectangle holding every pixel that is not fully
+    /// transparent as `(x, y, width, height)`, or `None` if the whole image is
+    /// transparent
+    pub fn content_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let width = self.width.max(1) as usize;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (idx, pixel) in self.to_rgba8().iter().enumerate() {
+            if pixel[3] == 0 {
+                continue;
+            }
+
+            let (x, y) = (idx % width, idx / width);
+            bounds = Some(match bounds {
+                Some((left, top, right, bottom)) => {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                }
+                None => (x, y, x, y),
+            });
+        }
+
+        bounds.map(|(left, top, right, bottom)| {
+            (
+                left as u32,
+                top as u32,
+                (right - left + 1) as u32,
+                (bottom - top + 1) as u32,
+            )
+        })
+    }
+
+    /// A 64-bit perceptual hash for finding near-duplicate images, the
+    /// Hamming distance between the hashes of similar images is small. Each
+    /// bit tells whether one cell of an 8x8 grid over the image is brighter
+    /// than the average of all cells.
+    pub fn phash(&self) -> u64 {
+        let (width, height) = (self.width as usize, self.height as usize);
+        if width == 0 || height == 0 {
+            return 0;
+        }
+
+        let pixels = self.to_rgba8();
+        let span = |cell: usize, len: usize| {
+            let start = cell * len / 8;
+            start..((cell + 1) * len / 8).max(start + 1)
+        };
+        let mut cells = [0u64; 64];
+        for (idx, cell) in cells.iter_mut().enumerate() {
+            let (rows, cols) = (span(idx / 8, height), span(idx % 8, width));
+            let count = (rows.len() * cols.len()) as u64;
+            let mut sum = 0u64;
+            for y in rows {
+                for x in cols.clone() {
+                    let [r, g, b, _] = pixels[y * width + x];
+                    sum += luma(r, g, b) as u64;
+                }
+            }
+            *cell = sum / count;
+        }
+
+        let average = cells.iter().sum::<u64>() / 64;
+        cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| **cell > average)
+            .fold(0, |hash, (idx, _)| hash | 1 << idx)
+    }
+
+    /// The average color of the image, with each pixel weighted by its alpha
+    pub fn average_color(&self) -> [u8; 4] {
+        let pixels = self.to_rgba8();
+        if pixels.is_empty() {
+            return [0; 4];
+        }
+
+        let (mut red, mut green, mut blue, mut alpha) = (0u64, 0u64, 0u64, 0u64);
+        for [r, g, b, a] in &pixels {
+            red += *r as u64 * *a as u64;
+            green += *g as u64 * *a as u64;
+            blue += *b as u64 * *a as u64;
+            alpha += *a as u64;
+        }
+
+        if alpha == 0 {
+            return [0; 4];
+        }
+
+        [
+            (red / alpha) as u8,
+            (green / alpha) as u8,
+            (blue / alpha) as u8,
+            (alpha / pixels.len() as u64) as u8,
+        ]
+    }
+
+    /// Converts the image to the `target` color type. Grey is replicated into
+    /// RGB, color is reduced to grey by its luma, and alpha is added as fully
+    /// opaque or dropped. Palette images can be expanded but not created since
+    /// that requires quantization.
+    pub fn convert(&self, target: ColorType) -> io::Result<Self> {
+        if target == self.color_type {
+            return Ok(self.clone());
+        }
+        if target == ColorType::PaletteIndex {
+            pngerr!(
+                "can not convert {} to {} without quantization",
+                self.color_type,
+                target
+            );
+        }
+
+        let mut data =
+            Vec::with_capacity(self.width as usize * self.height as usize * target.channels());
+        for [r, g, b, a] in self.to_rgba8() {
+            match target {
+                ColorType::Greyscale => data.push(luma(r, g, b)),
+                ColorType::GreyscaleAlpha => data.extend([luma(r, g, b), a]),
+                ColorType::RGB => data.extend([r, g, b]),
+                ColorType::RGBA => data.extend([r, g, b, a]),
+                ColorType::PaletteIndex => unreachable!(),
+            }
+        }
+
+        // carry the background color over in the representation of the target
+        let background = self.background_rgb().map(|[r, g, b]| match target {
+            ColorType::Greyscale | ColorType::GreyscaleAlpha => {
+                Bkgd::Greyscale(luma(r, g, b) as u16)
+            }
+            _ => Bkgd::Rgb(r as u16, g as u16, b as u16),
+        });
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            bit_depth: 8,
+            color_type: target,
+            interlaced: false,
+            data,
+            plte: None,
+            background,
+            transparancy: None,
+            physical: self.physical,
+            orientation: self.orientation,
+            suggested_palettes: self.suggested_palettes.clone(),
+            text: self.text.clone(),
+            filter_clamps: self.filter_clamps,
+            filters_roundtrip: self.filters_roundtrip,
+        })
+    }
+
+    /// The bKGD color as 8-bit RGB
+    fn background_rgb(&self) -> Option<[u8; 3]> {
+        match self.background.as_ref()? {
+            Bkgd::PaletteIndex(idx) => {
+                let entry = self.plte.as_ref()?.get(*idx as usize)?;
+                Some([entry._red, entry._green, entry._blue])
+            }
+            Bkgd::Greyscale(grey) => Some([*grey as u8; 3]),
+            Bkgd::Rgb(r, g, b) => Some([*r as u8, *g as u8, *b as u8]),
+        }
+    }
+
+    /// Whether filtering the decoded scanlines again with the filter type each
+    /// one was stored with reproduces the decompressed image data byte for
+    /// byte, a self-test of the filter reconstruction. Checked while decoding
+    /// with `DecodeOptions::verify_filters`, before the pixels are converted,
+    /// and `None` for images decoded without it.
+    pub fn verify_roundtrip(&self) -> Option<bool> {
+        self.filters_roundtrip
+    }
+
+    /// An 8-bit RGBA image holding the absolute difference between each
+    /// channel of every pixel of this image and `other`, so identical images
+    /// diff to all zeros. The images must have the same dimensions.
+    pub fn diff(&self, other: &Image) -> io::Result<Image> {
+        if (self.width, self.height) != (other.width, other.height) {
+            pngerr!(
+                "can not diff a {}x{} image with a {}x{} image",
+                self.width,
+                self.height,
+                other.width,
+                other.height
+            );
+        }
+
+        let data = self
+            .to_rgba8()
+            .iter()
+            .zip(other.to_rgba8())
+            .flat_map(|(a, b)| [0, 1, 2, 3].map(|idx| a[idx].abs_diff(b[idx])))
+            .collect();
+
+        Ok(Self {
+            width: self.width,
+            height: self.height,
+            bit_depth: 8,
+            color_type: ColorType::RGBA,
+            data,
+            ..Self::empty()
+        })
+    }
+
+    /// Number of distinct RGBA values in the image, counting stops at
+    /// `MAX_UNIQUE_COLORS` to bound memory use
+    pub fn unique_color_count(&self) -> usize {
+        let mut colors = HashSet::new();
+        for idx in 0..self.width as usize * self.height as usize {
+            colors.insert(self.rgba_at(idx));
+            if colors.len() == MAX_UNIQUE_COLORS {
+                break;
+            }
+        }
+        colors.len()
+    }
+
+    /// Brightness of pixel `c` in the 8-bit `scanline` by `method`
+    fn grey_at(&self, scanline: &[u8], c: usize, method: LumaMethod) -> u8 {
+        let idx = c * self.color_type.channels();
+        match self.color_type {
+            ColorType::Greyscale => scanline[idx],
+            ColorType::RGB => method.luma(scanline[idx], scanline[idx + 1], scanline[idx + 2]),
+            ColorType::PaletteIndex => {
+                let plte = self.plte.as_ref().unwrap();
+                let entry = &plte[scanline[idx] as usize];
+
+                method.luma(entry._red, entry._green, entry._blue)
+            }
+            // the flat average has always counted the alpha as a channel
+            ColorType::GreyscaleAlpha if method == LumaMethod::Average => {
+                ((scanline[idx] as u16 + scanline[idx + 1] as u16) / 2) as u8
+            }
+            ColorType::GreyscaleAlpha => scanline[idx],
+            ColorType::RGBA if method == LumaMethod::Average => {
+                ((scanline[idx] as u32
+                    + scanline[idx + 1] as u32
+                    + scanline[idx + 2] as u32
+                    + scanline[idx + 3] as u32)
+                    / 4) as u8
+            }
+            ColorType::RGBA => method.luma(scanline[idx], scanline[idx + 1], scanline[idx + 2]),
+        }
+    }
+
+    /// RGBA value of the pixel at index `idx` in row-major order
+    fn rgba_at(&self, idx: usize) -> [u8; 4] {
+        let data = &self.data;
+        match self.color_type {
+            ColorType::Greyscale => {
+                let grey = data[idx];
+                let alpha = match self.transparancy {
+                    Some(Transparancy::Greyscale(value)) if value == grey as u16 => 0,
+                    _ => 255,
+                };
+                [grey, grey, grey, alpha]
+            }
+            ColorType::RGB => {
+                let (r, g, b) = (data[idx * 3], data[idx * 3 + 1], data[idx * 3 + 2]);
+                let alpha = match self.transparancy {
+                    Some(Transparancy::Rgb(tr, tg, tb))
+                        if (tr, tg, tb) == (r as u16, g as u16, b as u16) =>
+                    {
+                        0
+                    }
+                    _ => 255,
+                };
+                [r, g, b, alpha]
+            }
+            ColorType::PaletteIndex => {
+                let index = data[idx] as usize;
+                let entry = &self.plte.as_ref().unwrap()[index];
+                let alpha = match &self.transparancy {
+                    // entries past the end of tRNS are opaque
+                    Some(Transparancy::PaletteIndex(alphas)) => {
+                        alphas.get(index).copied().unwrap_or(255)
+                    }
+                    _ => 255,
+                };
+                [entry._red, entry._green, entry._blue, alpha]
+            }
+            ColorType::GreyscaleAlpha => {
+                let grey = data[idx * 2];
+                [grey, grey, grey, data[idx * 2 + 1]]
+            }
+            ColorType::RGBA => [
+                data[idx * 4],
+                data[idx * 4 + 1],
+                data[idx * 4 + 2],
+                data[idx * 4 + 3],
+            ],
+        }
+    }
+}
+
+/// Decodes the PNG at `path` and renders it as ASCII art with the default
+/// options, one line per row
+pub fn file_to_ascii(path: &str) -> io::Result<String> {
+    Ok(Img::new(path)?.to_ascii_string(&RenderOptions::default()))
+}
+
+/// Checks that `bytes` holds a structurally valid PNG, walking every chunk
+/// without inflating or unfiltering the image data. CRC mismatches and other
+/// recoverable violations are only errors when `opts` is not lenient.
+pub fn validate(bytes: &[u8], opts: &DecodeOptions) -> io::Result<()> {
+    let mut chunks = ImageHelper::from_bytes(bytes.to_vec(), opts)?;
+    Image::read_chunks(&mut chunks, opts, &mut Vec::new())?;
+    Ok(())
+}
+
+/// 5.2 - IHDR is the first chunk and appears only once, `seen_ihdr` tracks
+/// whether it has been read as each chunk comes in
+fn check_ihdr_order(is_ihdr: bool, seen_ihdr: &mut bool) -> io::Result<()> {
+    match (is_ihdr, *seen_ihdr) {
+        (true, true) => {
+            pngerr!("PNG must not have more than one IHDR chunk");
+        }
+        (false, false) => {
+            pngerr!("IHDR chunk must come first");
+        }
+        _ => {}
+    }
+
+    *seen_ihdr = true;
+    Ok(())
+}
+
+/// Statistics gathered while decoding
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DecodeStats {
+    /// filter reconstructions that overflowed and were clamped when decoding
+    /// with `saturating_filters`
+    pub filter_clamps: usize,
+}
+
+/// Time spent in each phase of decoding a PNG
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchResult {
+    /// reading and validating the chunks
+    pub parse: Duration,
+
+    /// inflating the image data
+    pub decompress: Duration,
+
+    /// reversing the scanline filters
+    pub unfilter: Duration,
+}
+
+/// Decodes the PNG in `bytes` timing each phase, without building the ASCII grid
+pub fn bench_decode(bytes: &[u8]) -> io::Result<BenchResult> {
+    let opts = DecodeOptions::default();
+    let diagnostics = &mut Vec::new();
+    let data = bytes.to_vec();
+
+    let start = Instant::now();
+    let mut chunks = ImageHelper::from_bytes(data, &opts)?;
+    let (mut image, compressed_data) = Image::read_chunks(&mut chunks, &opts, diagnostics)?;
+    let parse = start.elapsed();
+
+    let start = Instant::now();
+    let mut decoder = ZlibDecoder::new(&compressed_data[..]);
+    let mut filtered = Vec::new();
+    decoder.read_to_end(&mut filtered)?;
+    let decompress = start.elapsed();
+
+    let start = Instant::now();
+    reverse_filter(filtered, &mut image, &opts, diagnostics, &mut |_| {})?;
+    let unfilter = start.elapsed();
+
+    Ok(BenchResult {
+        parse,
+        decompress,
+        unfilter,
+    })
+}
+
+/// Inflates a zlib stream compressed with a preset `dictionary`, which
+/// `ZlibDecoder` has no way to be given
+fn inflate_with_dictionary(compressed: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let Some(dictionary) = dictionary else {
+        pngerr!("image data needs a preset zlib dictionary");
+    };
+
+    // RFC 1950: the two header bytes are followed by the Adler-32 checksum of
+    // the dictionary, and the compressed data by that of the inflated data
+    if compressed.len() < 10 {
+        pngerr!("image data is too short for a zlib stream");
+    }
+    if read_u32_be(compressed, 2)? != adler32(dictionary) {
+        pngerr!("image data needs a different preset zlib dictionary");
+    }
+
+    // raw deflate data preceeded by stored blocks holding the dictionary, so
+    // that back references into it resolve
+    let mut prefix = Vec::new();
+    for block in dictionary.chunks(u16::MAX as usize) {
+        let len = block.len() as u16;
+        prefix.push(0);
+        prefix.extend(len.to_le_bytes());
+        prefix.extend((!len).to_le_bytes());
+        prefix.extend(block);
+    }
+    let data = &compressed[6..compressed.len() - 4];
+    let mut inflated = Vec::new();
+    DeflateDecoder::new((&prefix[..]).chain(data)).read_to_end(&mut inflated)?;
+
+    let inflated = inflated.split_off(dictionary.len());
+    if read_u32_be(compressed, compressed.len() - 4)? != adler32(&inflated) {
+        pngerr!("image data has an Adler-32 mismatch");
+    }
+
+    Ok(inflated)
+}
+
+/// RFC 1950 - section 8.2
+fn adler32(data: &[u8]) -> u32 {
+    const BASE: u32 = 65521;
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % BASE;
+        b = (b + a) % BASE;
+    }
+    b << 16 | a
+}
+
+/// Makes sure every palette index used by the image data has a PLTE entry,
+/// out of range indices are an error unless decoding leniently
+fn check_palette_indices(
+    image: &mut Image,
+    opts: &DecodeOptions,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> io::Result<()> {
+    let plte = image.plte.as_mut().unwrap();
+    let plte_len = plte.len();
+    if image.data.iter().all(|idx| (*idx as usize) < plte_len) {
+        return Ok(());
+    }
+
+    let message = "image data references a palette index with no PLTE entry".to_owned();
+    violation(opts.lenient, diagnostics, None, message)?;
+
+    let fallback = match opts.palette_fallback {
+        Some([red, green, blue]) => {
+            // point out of range indices to an extra entry holding the fallback color
+            plte.push(PLTEEntry {
+                _red: red,
+                _green: green,
+                _blue: blue,
+            });
+            if let Some(Transparancy::PaletteIndex(alphas)) = image.transparancy.as_mut() {
+                alphas.push(255);
+            }
+            plte_len as u8
+        }
+        None if plte_len > 0 => (plte_len - 1) as u8,
+        None => {
+            pngerr!("PLTE chunk has no entries to fall back to");
+        }
+    };
+
+    for idx in image.data.iter_mut() {
+        if *idx as usize >= plte_len {
+            *idx = fallback;
+        }
+    }
+
+    Ok(())
+}
+
+/// Spreads the packed samples of bit depths lower than 8 out to a byte each,
+/// dropping the unused bits at the end of each scanline. Palette indices are
+/// kept as they are while greyscale samples are stretched over 0-255, along
+/// with the tRNS and bKGD greys so they still match the data.
+fn unpack_samples(image: &mut Image) {
+    if image.bit_depth >= 8 {
+        return;
+    }
+
+    let mut samples = image.unpacked_samples();
+    if let ColorType::Greyscale = image.color_type {
+        let scale = grey_scale(image.bit_depth);
+        for sample in samples.iter_mut() {
+            *sample *= scale;
+        }
+        if let Some(Transparancy::Greyscale(grey)) = image.transparancy.as_mut() {
+            *grey *= scale as u16;
+        }
+        if let Some(Bkgd::Greyscale(grey)) = image.background.as_mut() {
+            *grey *= scale as u16;
+        }
+    }
+
+    image.data = samples;
+    image.bit_depth = 8;
+}
+
+/// Factor stretching greyscale samples of a bit depth lower than 8 over
+/// 0-255, so a 1-bit sample becomes 0 or 255
+fn grey_scale(bit_depth: u8) -> u8 {
+    255 / ((1u8 << bit_depth) - 1)
+}
+
+/// Appends the first `width` samples of a scanline of `bits`-bit samples to
+/// `out`, unpacking them from the most significant bit of each byte
+fn unpack_row(row: &[u8], width: usize, bits: usize, out: &mut Vec<u8>) {
+    let mask = (1u8 << bits) - 1;
+    for x in 0..width {
+        let shift = 8 - bits - (x * bits) % 8;
+        out.push((row[x * bits / 8] >> shift) & mask);
+    }
+}
+
+/// Gives a greyscale or RGB image with a tRNS color an alpha channel in place
+/// of the tRNS chunk, fully transparent where the samples equal the color in
+/// every bit and opaque elsewhere
+fn expand_color_key(image: &mut Image) {
+    let key = match image.transparancy {
+        Some(Transparancy::Greyscale(grey)) => vec![grey],
+        Some(Transparancy::Rgb(red, green, blue)) => vec![red, green, blue],
+        _ => return,
+    };
+
+    // samples are 8 or 16-bit big-endian by now
+    let bytes = image.bit_depth as usize / 8;
+    let channels = key.len();
+    let mut data = Vec::with_capacity(image.data.len() / channels * (channels + 1));
+    for pixel in image.data.chunks_exact(channels * bytes) {
+        let samples = pixel.chunks_exact(bytes).map(|sample| {
+            sample
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as u16)
+        });
+        let alpha = if samples.eq(key.iter().copied()) {
+            0
+        } else {
+            255
+        };
+        data.extend(pixel);
+        data.extend(std::iter::repeat_n(alpha, bytes));
+    }
+
+    image.data = data;
+    image.transparancy = None;
+    image.color_type = match image.color_type {
+        ColorType::Greyscale => ColorType::GreyscaleAlpha,
+        _ => ColorType::RGBA,
+    };
+}
+
+/// Converts from 16-bit colors to 8-bit colors, by which point a tRNS color
+/// has become an alpha channel
+fn convert_bit_depth(image: &mut Image) {
+    if image.bit_depth != 16 {
+        return;
+    }
+
+    image.data = image.data.chunks_exact(2).map(|sample| sample[0]).collect();
+    image.bit_depth = 8;
+
+    // keep the bKGD sample values comparable with the converted data
+    match image.background.as_mut() {
+        Some(Bkgd::Greyscale(grey)) => *grey /= 256,
+        Some(Bkgd::Rgb(red, green, blue)) => {
+            *red /= 256;
+            *green /= 256;
+            *blue /= 256;
+        }
+        _ => {}
+    }
+}
+
+/// Blends every pixel of a greyscale with alpha or RGBA image over
+/// `background`, dropping the alpha channel. Palette images have their
+/// entries blended with the tRNS alphas instead.
+fn composite(image: &mut Image, background: [u8; 3]) {
+    let (colors, target) = match image.color_type {
+        ColorType::GreyscaleAlpha => (1, ColorType::Greyscale),
+        ColorType::RGBA => (3, ColorType::RGB),
+        ColorType::PaletteIndex => {
+            if let Some(Transparancy::PaletteIndex(alphas)) = image.transparancy.take() {
+                let blend = |color: &mut u8, background: u8, alpha: u8| {
+                    let (color_part, alpha) = (*color as u32 * alpha as u32, alpha as u32);
+                    *color = ((color_part + background as u32 * (255 - alpha) + 127) / 255) as u8;
+                };
+                let [r, g, b] = background;
+                for (entry, alpha) in image.plte.iter_mut().flatten().zip(alphas) {
+                    blend(&mut entry._red, r, alpha);
+                    blend(&mut entry._green, g, alpha);
+                    blend(&mut entry._blue, b, alpha);
+                }
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    let bytes = image.bit_depth as usize / 8;
+    let max = (1u32 << image.bit_depth) - 1;
+    let [r, g, b] = background;
+    let background: Vec<u32> = if colors == 1 {
+        vec![luma(r, g, b) as u32]
+    } else {
+        vec![r as u32, g as u32, b as u32]
+    };
+
+    // samples are big-endian
+    let sample = |pixel: &[u8], idx: usize| -> u32 {
+        pixel[idx * bytes..(idx + 1) * bytes]
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u32)
+    };
+
+    let mut data = Vec::with_capacity(image.data.len() / (colors + 1) * colors);
+    for pixel in image.data.chunks_exact((colors + 1) * bytes) {
+        let alpha = sample(pixel, colors);
+        for (idx, background) in background.iter().enumerate() {
+            let background = background * max / 255;
+            let value = (sample(pixel, idx) * alpha + background * (max - alpha) + max / 2) / max;
+            data.extend(&value.to_be_bytes()[4 - bytes..]);
+        }
+    }
+
+    image.data = data;
+    image.color_type = target;
+}
+
+/// Divides the color of every pixel of a greyscale with alpha or RGBA image
+/// by its alpha, undoing premultiplication
+fn unpremultiply(image: &mut Image) {
+    let colors = match image.color_type {
+        ColorType::GreyscaleAlpha => 1,
+        ColorType::RGBA => 3,
+        _ => return,
+    };
+
+    let bytes = image.bit_depth as usize / 8;
+    let max = (1u32 << image.bit_depth) - 1;
+
+    // samples are big-endian
+    let sample = |pixel: &[u8], idx: usize| -> u32 {
+        pixel[idx * bytes..(idx + 1) * bytes]
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u32)
+    };
+
+    for pixel in image.data.chunks_exact_mut((colors + 1) * bytes) {
+        let alpha = sample(pixel, colors);
+        for idx in 0..colors {
+            let value = match alpha {
+                0 => 0,
+                _ => ((sample(pixel, idx) * max + alpha / 2) / alpha).min(max),
+            };
+            pixel[idx * bytes..(idx + 1) * bytes]
+                .copy_from_slice(&value.to_be_bytes()[4 - bytes..]);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum FilterType {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+}
+
+impl FilterType {
+    fn from(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Sub),
+            2 => Ok(Self::Up),
+            3 => Ok(Self::Average),
+            4 => Ok(Self::Paeth),
+            _ => {
+                pngerr!("invalid filter type");
+            }
+        }
+    }
+}
+
+/// RFC 2083 - Section 6
+/// Unfilters the inflated image data into the image, calling `progress` with
+/// the fraction of scanlines done
+fn reverse_filter(
+    filtered: Vec<u8>,
+    image: &mut Image,
+    opts: &DecodeOptions,
+    diagnostics: &mut Vec<Diagnostic>,
+    progress: &mut dyn FnMut(f32),
+) -> io::Result<()> {
+    let format = image.pixel_format();
+    let (width, height) = (image.width as usize, image.height as usize);
+
+    // passes without any pixels have no scanlines, not even filter type bytes
+    let passes: Vec<_> = if image.interlaced {
+        ADAM7
+            .iter()
+            .map(|pass| (*pass, pass_dimensions(width, height, *pass)))
+            .filter(|(_, (width, height))| *width > 0 && *height > 0)
+            .collect()
+    } else {
+        vec![((0, 0, 1, 1), (width, height))]
+    };
+
+    let expected: usize = passes
+        .iter()
+        .map(|(_, (width, height))| (format.stride(*width) + 1) * height)
+        .sum();
+    if filtered.len() < expected {
+        pngerr!(
+            "image data is {} bytes, expected {}",
+            filtered.len(),
+            expected
+        );
+    }
+    if filtered.len() > expected {
+        let message = format!(
+            "image data has {} bytes past the last scanline",
+            filtered.len() - expected
+        );
+        violation(opts.lenient, diagnostics, Some("IDAT"), message)?;
+    }
+
+    if image.interlaced {
+        deinterlace(&filtered, image, &passes, opts, progress)?;
+        return Ok(());
+    }
+
+    // unfilter in batches of rows, each continuing from the last row of the
+    // one before
+    let width = format.stride(width) + 1;
+    let bpp = format.filter_bpp();
+    let batch = (height / 100).max(1);
+    for start in (0..height).step_by(batch) {
+        let rows = batch.min(height - start);
+        image.filter_clamps += unfilter(
+            &filtered[start * width..],
+            width,
+            rows,
+            bpp,
+            opts.saturating_filters,
+            &mut image.data,
+        )?;
+        progress((start + rows) as f32 / height as f32);
+    }
+    if opts.verify_filters {
+        image.filters_roundtrip = Some(refilter_matches(&filtered, width, bpp, &image.data));
+    }
+
+    Ok(())
+}
+
+/// The first pixel and the spacing between pixels of an interlacing pass
+/// over the image, as `(x, y, dx, dy)`
+type Pass = (usize, usize, usize, usize);
+
+/// RFC 2083 - Section 2.6: the seven passes of Adam7 interlacing
+const ADAM7: [Pass; 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Width and height in pixels of the Adam7 `pass` over a `width`x`height`
+/// image
+fn pass_dimensions(width: usize, height: usize, (x, y, dx, dy): Pass) -> (usize, usize) {
+    (
+        width.saturating_sub(x).div_ceil(dx),
+        height.saturating_sub(y).div_ceil(dy),
+    )
+}
+
+/// Unfilters each of the non-empty Adam7 `passes` as an image of its own,
+/// each pass given with its width and height, and places every pixel of the
+/// pass at its position in the full image
+fn deinterlace(
+    filtered: &[u8],
+    image: &mut Image,
+    passes: &[(Pass, (usize, usize))],
+    opts: &DecodeOptions,
+    progress: &mut dyn FnMut(f32),
+) -> io::Result<()> {
+    let format = image.pixel_format();
+    let (bits, bpp) = (format.bits_per_pixel(), format.filter_bpp());
+    let stride = format.stride(image.width as usize);
+
+    // reuse the buffer the pixels go into, as `Image::decode_into` relies on
+    image.data.clear();
+    image.data.resize(stride * image.height as usize, 0);
+    let mut roundtrip = true;
+    let mut offset = 0;
+    for (idx, ((x0, y0, dx, dy), (pass_width, pass_height))) in passes.iter().enumerate() {
+        let line = format.stride(*pass_width) + 1;
+        let len = line * pass_height;
+        let mut pass = Vec::with_capacity(len);
+        image.filter_clamps += unfilter(
+            &filtered[offset..],
+            line,
+            *pass_height,
+            bpp,
+            opts.saturating_filters,
+            &mut pass,
+        )?;
+        if opts.verify_filters {
+            roundtrip &= refilter_matches(&filtered[offset..offset + len], line, bpp, &pass);
+        }
+
+        for (y, row) in pass.chunks(line - 1).enumerate() {
+            let dst = &mut image.data[(y0 + y * dy) * stride..];
+            for x in 0..*pass_width {
+                copy_pixel(row, x, dst, x0 + x * dx, bits);
+            }
+        }
+
+        offset += len;
+        progress((idx + 1) as f32 / passes.len() as f32);
+    }
+
+    if opts.verify_filters {
+        image.filters_roundtrip = Some(roundtrip);
+    }
+    Ok(())
+}
+
+/// Copies pixel `sx` of the scanline `src` to pixel `x` of the scanline
+/// `dst`, both of `bits`-bit pixels packed from the most significant bit.
+/// The bits of the destination pixel must be clear.
+fn copy_pixel(src: &[u8], sx: usize, dst: &mut [u8], x: usize, bits: usize) {
+    if bits >= 8 {
+        let bytes = bits / 8;
+        dst[x * bytes..(x + 1) * bytes].copy_from_slice(&src[sx * bytes..(sx + 1) * bytes]);
+    } else {
+        let mask = (1u8 << bits) - 1;
+        let src_shift = 8 - bits - (sx * bits) % 8;
+        let dst_shift = 8 - bits - (x * bits) % 8;
+        let sample = (src[sx * bits / 8] >> src_shift) & mask;
+        dst[x * bits / 8] |= sample << dst_shift;
+    }
+}
+
+/// Whether applying the filter type of each scanline in `filtered` to the
+/// reconstructed `raw` bytes gives back exactly the filtered bytes
+fn refilter_matches(filtered: &[u8], width: usize, bpp: usize, raw: &[u8]) -> bool {
+    // scanlines without any bytes after the filter type have nothing to check
+    let stride = width.saturating_sub(1);
+    if stride == 0 {
+        return true;
+    }
+
+    raw.chunks(stride).enumerate().all(|(r, row)| {
+        let offset = r * width;
+        let row_start = r * stride;
+        (0..row.len()).all(|c| {
+            let (left, top, top_left) = neighbours(raw, row_start, c, stride, bpp);
+            let prediction = match FilterType::from(filtered[offset]) {
+                Ok(FilterType::None) => 0,
+                Ok(FilterType::Sub) => left,
+                Ok(FilterType::Up) => top,
+                Ok(FilterType::Average) => ((left as u16 + top as u16) / 2) as u8,
+                Ok(FilterType::Paeth) => paeth_predictor(left, top, top_left),
+                Err(_) => return false,
+            };
+            row[c].wrapping_sub(prediction) == filtered[offset + 1 + c]
+        })
+    })
+}
+
+/// Reconstructs `rows` scanlines of `width` bytes each, including the leading
+/// filter type byte, appending the raw bytes to `out`. The last scanline
+/// already in `out` is the prior row of the first one.
+fn unfilter(
+    filtered: &[u8],
+    width: usize,
+    rows: usize,
+    bpp: usize,
+    saturating: bool,
+    out: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let mut clamps = 0;
+    for r in 0..rows {
+        let offset = r * width;
+        let filter_type = FilterType::from(filtered[offset])?;
+        let row_start = out.len();
+        for c in 1..width {
+            let x = filtered[offset + c];
+            let (left, top, top_left) = neighbours(out, row_start, c - 1, width - 1, bpp);
+            let prediction = match filter_type {
+                FilterType::None => 0,
+                // Section 6.3: Raw(x) = Sub(x) + Raw(x-bpp)
+                FilterType::Sub => left,
+                // Section 6.4: Raw(x) = Up(x) + Prior(x)
+                FilterType::Up => top,
+                // Section 6.5: Raw(x) = Average(x) + floor((Raw(x-bpp)+Prior(x))/2)
+                FilterType::Average => ((left as u16 + top as u16) / 2) as u8,
+                // Section 6.6: Raw(x) = Paeth(x) + PaethPredictor(Raw(x-bpp), Prior(x), Prior(x-bpp))
+                FilterType::Paeth => paeth_predictor(left, top, top_left),
+            };
+            let raw_x = match x.checked_add(prediction) {
+                Some(raw_x) => raw_x,
+                None if saturating => {
+                    clamps += 1;
+                    u8::MAX
+                }
+                None => x.wrapping_add(prediction),
+            };
+            out.push(raw_x);
+        }
+    }
+
+    Ok(clamps)
+}
+
+/// Returns the already reconstructed `Raw(x-bpp)`, `Prior(x)`, and
+/// `Prior(x-bpp)` bytes for column `c` of the scanline starting at `row_start`.
+/// Bytes left of the scanline and the prior row of the first scanline do not
+/// exist and are treated as zeros (RFC 2083 - Section 6.1)
+fn neighbours(data: &[u8], row_start: usize, c: usize, stride: usize, bpp: usize) -> (u8, u8, u8) {
+    let has_left = c >= bpp;
+    let has_prior = row_start >= stride;
+    let left = if has_left {
+        data[row_start + c - bpp]
+    } else {
+        0
+    };
+    let top = if has_prior {
+        data[row_start - stride + c]
+    } else {
+        0
+    };
+    let top_left = if has_left && has_prior {
+        data[row_start - stride + c - bpp]
+    } else {
+        0
+    };
+
+    (left, top, top_left)
+}
+
+fn paeth_predictor(left: u8, top: u8, top_left: u8) -> u8 {
+    let p = left as i16 + top as i16 - top_left as i16;
+    let pleft = 0i16.abs_diff(p - left as i16);
+    let ptop = 0i16.abs_diff(p - top as i16);
+    let ptop_left = 0i16.abs_diff(p - top_left as i16);
+
+    if pleft <= ptop && pleft <= ptop_left {
+        left
+    } else if ptop <= ptop_left {
+        top
+    } else {
+        top_left
+    }
+}
+
+impl Display for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Dimention: {}x{}px\nColor Type: {}\nBit Depth: {}\nInterlaced: {}\nData Size: {} bytes",
+            self.width,
+            self.height,
+            self.color_type,
+            self.bit_depth,
+            if self.interlaced { "Yes" } else { "No" },
+            self.data.len()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Bkgd {
+    PaletteIndex(u8),
+    Greyscale(u16),
+    Rgb(u16, u16, u16),
+}
+
+enum Chunk<'a> {
+    Ihdr(IHDRData),
+    Plte(Vec<PLTEEntry>),
+    Idat(&'a [u8]),
+    Iend,
+    Bkgd(Bkgd),
+    Chrm,
+    Gama,
+    Hist,
+    Phys(PHYSData),
+    Sbit,
+    Text(TextEntry),
+    Time,
+    Trns(&'a [u8]),
+    Exif(Option<u16>),
+    Splt(SuggestedPalette),
+
+    /// an ancillary chunk this decoder does not know, safe to skip
+    Unknown,
+}
+
+impl<'a> Chunk<'a> {
+    fn new(image: &'a mut ImageHelper, diagnostics: &mut Vec<Diagnostic>) -> io::Result<Self> {
+        // length, type, and CRC take 12 bytes around the data
+        let remaining = image.data.len() - image.offset;
+        if remaining < 8 {
+            pngerr!(
+                "file ends in the middle of a chunk header at byte {}",
+                image.offset
+            );
+        }
+        let len = read_u32_be(&image.data, image.offset)? as usize;
+        if remaining < len + 12 {
+            pngerr!(
+                "{} chunk at byte {} needs {} bytes, but the file ends {} bytes into it",
+                String::from_utf8_lossy(&image.data[image.offset + 4..image.offset + 8]),
+                image.offset,
+                len + 12,
+                remaining
+            );
+        }
+        image.offset += 4;
+
+        // get type
+        image.offset += 4;
+        let data = &image.data[image.offset..image.offset + len];
+        let chunk_type = &image.data[image.offset - 4..image.offset];
+        let chunk = match chunk_type {
+            b"IHDR" => Self::Ihdr(IHDRData::from(data)?),
+            b"PLTE" => {
+                if !len.is_multiple_of(3) {
+                    pngerr!("invalid PLTE chunk");
+                }
+
+                let mut entries = Vec::new();
+                let mut idx = 0usize;
+                loop {
+                    if idx == len {
+                        break;
+                    }
+
+                    entries.push(PLTEEntry {
+                        _red: data[idx],
+                        _green: data[idx + 1],
+                        _blue: data[idx + 2],
+                    });
+
+                    idx += 3;
+                }
+                Self::Plte(entries)
+            }
+            b"IDAT" => Self::Idat(data),
+            b"IEND" => {
+                if len != 0 {
+                    pngerr!("IEND chunk must not contain any data");
+                }
+                Self::Iend
+            }
+            b"bKGD" => match len {
+                1 => Self::Bkgd(Bkgd::PaletteIndex(data[0])),
+                2 => Self::Bkgd(Bkgd::Greyscale(read_u16_be(data, 0)?)),
+                6 => Self::Bkgd(Bkgd::Rgb(
+                    read_u16_be(data, 0)?,
+                    read_u16_be(data, 2)?,
+                    read_u16_be(data, 4)?,
+                )),
+                _ => {
+                    pngerr!("invalid bKGD chunk");
+                }
+            },
+            b"cHRM" => Self::Chrm,
+            b"gAMA" => Self::Gama,
+            b"hIST" => Self::Hist,
+            b"pHYs" => {
+                if len != 9 {
+                    pngerr!("invalid pHYs chunk");
+                }
+
+                Self::Phys(PHYSData {
+                    x_ppu: read_u32_be(data, 0)?,
+                    y_ppu: read_u32_be(data, 4)?,
+                    unit_is_meter: data[8] == 1,
+                })
+            }
+            b"sBIT" => Self::Sbit,
+            b"tEXt" => Self::Text(TextEntry::from_text(data)?),
+            b"iTXt" => Self::Text(TextEntry::from_international_text(
+                data,
+                image.lenient,
+                diagnostics,
+            )?),
+            b"tIME" => Self::Time,
+            b"tRNS" => Self::Trns(data),
+            b"zTXt" => Self::Text(TextEntry::from_compressed_text(
+                data,
+                image.lenient,
+                diagnostics,
+            )?),
+            b"eXIf" => Self::Exif(exif_orientation(data)),
+            b"sPLT" => Self::Splt(SuggestedPalette::from(data)?),
+            _ => {
+                let name = String::from_utf8_lossy(chunk_type);
+                if !chunk_type.iter().all(u8::is_ascii_alphabetic) {
+                    pngerr!("{} is an invalid PNG chunk type", name);
+                }
+
+                // the case of each letter is a property bit, lowercase sets it:
+                // ancillary, private, reserved, and safe-to-copy. Only the
+                // first matters for decoding, the rest are for editors.
+                let ancillary = chunk_type[0] & 0x20 != 0;
+                if !ancillary {
+                    pngerr!("{} is an unknown critical PNG chunk", name);
+                }
+                Self::Unknown
+            }
+        };
+        image.offset += len;
+
+        // verify the CRC, calculated over the chunk type and data, even for
+        // chunks whose content is thrown away
+        let expected = read_u32_be(&image.data, image.offset)?;
+        let mut crc = Crc::new();
+        crc.update(&image.data[image.offset - len - 4..image.offset]);
+        if crc.sum() != expected {
+            let chunk_type = String::from_utf8_lossy(&image.data[image.offset - len - 4..][..4]);
+            let message = format!("{} chunk has a CRC mismatch", chunk_type);
+            violation(image.lenient, diagnostics, Some(&chunk_type), message)?;
+        }
+        image.offset += 4;
+
+        Ok(chunk)
+    }
+}
+
+/// Whether the content of chunks of `chunk_type` is never used, which holds for
+/// every ancillary chunk other than the few the decoder reads
+fn is_ignored(chunk_type: &[u8]) -> bool {
+    const USED: [&[u8]; 8] = [
+        b"bKGD", b"pHYs", b"tEXt", b"zTXt", b"iTXt", b"tRNS", b"eXIf", b"sPLT",
+    ];
+
+    chunk_type.iter().all(u8::is_ascii_alphabetic)
+        && chunk_type[0] & 0x20 != 0
+        && !USED.contains(&chunk_type)
+}
+
+/// A keyword and text pair from a tEXt, zTXt, or iTXt chunk
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextEntry {
+    /// what the text is about, e.g. Title, Author, or Description
+    pub keyword: String,
+
+    pub text: String,
+
+    /// whether the text was stored compressed (zTXt and compressed iTXt)
+    pub compressed: bool,
+
+    /// the language of the text as an RFC 3066 tag, iTXt only and possibly
+    /// empty
+    pub language: Option<String>,
+
+    /// the keyword translated into `language`, iTXt only and possibly empty
+    pub translated_keyword: Option<String>,
+}
+
+impl TextEntry {
+    /// tEXt Chunk - PNG Specification 1.2 (section 4.2.3.1)
+    fn from_text(data: &[u8]) -> io::Result<Self> {
+        let (keyword, text) = split_keyword(data, "tEXt")?;
+        Ok(Self {
+            keyword,
+            text: latin1(text),
+            compressed: false,
+            language: None,
+            translated_keyword: None,
+        })
+    }
+
+    /// zTXt Chunk - PNG Specification 1.2 (section 4.2.3.2)
+    fn from_compressed_text(
+        data: &[u8],
+        lenient: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<Self> {
+        let (keyword, rest) = split_keyword(data, "zTXt")?;
+
+        // zlib is the only compression method
+        if rest.first() != Some(&0) {
+            pngerr!("invalid zTXt chunk compression method");
+        }
+        let inflated = inflate_text(&rest[1..], "zTXt", lenient, diagnostics)?;
+
+        Ok(Self {
+            keyword,
+            text: latin1(&inflated),
+            compressed: true,
+            language: None,
+            translated_keyword: None,
+        })
+    }
+
+    /// iTXt Chunk - PNG Specification 1.2 (section 4.2.3.3)
+    fn from_international_text(
+        data: &[u8],
+        lenient: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<Self> {
+        let (keyword, rest) = split_keyword(data, "iTXt")?;
+        if rest.len() < 2 {
+            pngerr!("invalid iTXt chunk");
+        }
+        let compressed = rest[0] == 1;
+
+        // the language tag is ASCII and the translated keyword UTF-8
+        let mut fields = rest[2..].splitn(3, |byte| *byte == 0);
+        let (language, translated_keyword, text) =
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(language), Some(translated_keyword), Some(text)) => {
+                    (language, translated_keyword, text)
+                }
+                _ => {
+                    pngerr!("invalid iTXt chunk");
+                }
+            };
+        let language = latin1(language);
+        let Ok(translated_keyword) = String::from_utf8(translated_keyword.to_vec()) else {
+            pngerr!("iTXt chunk translated keyword is not valid UTF-8");
+        };
+
+        let text = if compressed {
+            let mut inflated = inflate_text(text, "iTXt", lenient, diagnostics)?;
+            // text cut short at the cap may end in part of a character
+            if inflated.len() == MAX_TEXT_LEN {
+                if let Err(err) = std::str::from_utf8(&inflated) {
+                    if err.error_len().is_none() {
+                        inflated.truncate(err.valid_up_to());
+                    }
+                }
+            }
+            inflated
+        } else {
+            text.to_vec()
+        };
+
+        match String::from_utf8(text) {
+            Ok(text) => Ok(Self {
+                keyword,
+                text,
+                compressed,
+                language: Some(language),
+                translated_keyword: Some(translated_keyword),
+            }),
+            Err(_) => {
+                pngerr!("iTXt chunk text is not valid UTF-8");
+            }
+        }
+    }
+}
+
+/// Inflates the compressed text of a `chunk` chunk, cutting it short at
+/// `MAX_TEXT_LEN` bytes
+fn inflate_text(
+    compressed: &[u8],
+    chunk: &str,
+    lenient: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(compressed)
+        .take(MAX_TEXT_LEN as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > MAX_TEXT_LEN {
+        inflated.truncate(MAX_TEXT_LEN);
+        let message = format!(
+            "{} chunk text inflates to more than {} bytes",
+            chunk, MAX_TEXT_LEN
+        );
+        violation(lenient, diagnostics, Some(chunk), message)?;
+    }
+
+    Ok(inflated)
+}
+
+/// Splits the null-terminated Latin-1 keyword that starts text chunks from the
+/// rest of the chunk data
+fn split_keyword<'a>(data: &'a [u8], chunk: &str) -> io::Result<(String, &'a [u8])> {
+    match data.iter().position(|byte| *byte == 0) {
+        Some(len @ 1..=79) => Ok((latin1(&data[..len]), &data[len + 1..])),
+        _ => {
+            pngerr!("invalid {} chunk keyword", chunk);
+        }
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| *byte as char).collect()
+}
+
+/// sPLT Chunk - PNG Specification 1.2 (section 4.2.12)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuggestedPalette {
+    /// name of the palette, unique within the image
+    pub name: String,
+
+    /// 8 or 16, the depth of each entry's color and alpha samples
+    pub sample_depth: u8,
+
+    pub entries: Vec<SuggestedPaletteEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuggestedPaletteEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+
+    /// how often the color appears in the image relative to the other entries
+    pub frequency: u16,
+}
+
+impl SuggestedPalette {
+    fn from(data: &[u8]) -> io::Result<Self> {
+        let name_len = match data.iter().position(|byte| *byte == 0) {
+            Some(len @ 1..=79) => len,
+            _ => {
+                pngerr!("invalid sPLT chunk name");
+            }
+        };
+        let name = latin1(&data[..name_len]);
+
+        let sample_depth = match data.get(name_len + 1) {
+            Some(depth @ (8 | 16)) => *depth,
+            _ => {
+                pngerr!("invalid sPLT chunk sample depth");
+            }
+        };
+
+        let entries = &data[name_len + 2..];
+        let entry_len = if sample_depth == 8 { 6 } else { 10 };
+        if !entries.len().is_multiple_of(entry_len) {
+            pngerr!("invalid sPLT chunk");
+        }
+
+        let entries = entries
+            .chunks(entry_len)
+            .map(|entry| {
+                let sample = |i: usize| {
+                    if sample_depth == 8 {
+                        entry[i] as u16
+                    } else {
+                        u16::from_be_bytes([entry[i * 2], entry[i * 2 + 1]])
+                    }
+                };
+                SuggestedPaletteEntry {
+                    red: sample(0),
+                    green: sample(1),
+                    blue: sample(2),
+                    alpha: sample(3),
+                    frequency: u16::from_be_bytes([entry[entry_len - 2], entry[entry_len - 1]]),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            name,
+            sample_depth,
+            entries,
+        })
+    }
+}
+
+/// Reads the orientation tag from the first IFD of the EXIF data in an eXIf
+/// chunk, malformed EXIF data is treated as having no orientation
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x0112;
+
+    let big_endian = match data.get(0..4)? {
+        [b'M', b'M', 0, 42] => true,
+        [b'I', b'I', 42, 0] => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| -> Option<u16> {
+        let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|entry| ifd + 2 + entry * 12)
+        .find(|entry| u16_at(*entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Reads the big-endian u16 at `offset` in `data`
+fn read_u16_be(data: &[u8], offset: usize) -> io::Result<u16> {
+    match data.get(offset..).and_then(|rest| rest.get(..2)) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => {
+            pngerr!("chunk ends before a 2-byte field at offset {}", offset);
+        }
+    }
+}
+
+/// Reads the big-endian u32 at `offset` in `data`
+fn read_u32_be(data: &[u8], offset: usize) -> io::Result<u32> {
+    match data.get(offset..).and_then(|rest| rest.get(..4)) {
+        Some(bytes) => Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => {
+            pngerr!("chunk ends before a 4-byte field at offset {}", offset);
+        }
+    }
+}
+
+/// IHDR Chunk - RFC 2083 (section 4.1.1)
+pub struct IHDRData {
+    /// width in pixels
+    width: u32,
+
+    /// height in pixels
+    height: u32,
+
+    /// the number of bits per sample or per palette index (not per pixel)
+    bit_depth: u8,
+
+    /// the interpretation of the image data
+    color_type: ColorType,
+
+    /// a boolean value to determine if image is interlaced or not
+    /// the only available interlace type is "Adam7 interlace"
+    interlace_method: bool,
+}
+
+impl IHDRData {
+    /// Reads the header of the PNG in `bytes` without looking at the rest of
+    /// the file
+    pub fn read(bytes: &[u8]) -> io::Result<Self> {
+        // the signature followed by the IHDR chunk
+        let header_len = PNG_HDR.len() + 12 + 13;
+        if bytes.len() < header_len {
+            pngerr!("file is too short to hold an IHDR chunk");
+        }
+
+        let mut chunks =
+            ImageHelper::from_bytes(bytes[..header_len].to_vec(), &DecodeOptions::default())?;
+        match chunks.next(&mut Vec::new())? {
+            Some(Chunk::Ihdr(header)) => Ok(header),
+            _ => {
+                pngerr!("missing IHDR chunk");
+            }
+        }
+    }
+
+    fn from(data: &[u8]) -> io::Result<Self> {
+        if data.len() != 13 {
+            pngerr!("IHDR chunk is {} bytes, expected 13", data.len());
+        }
+
+        let idhr = Self {
+            width: read_u32_be(data, 0)?,
+            height: read_u32_be(data, 4)?,
+            bit_depth: data[8],
+            color_type: match data[9] {
+                0 => ColorType::Greyscale,
+                2 => ColorType::RGB,
+                3 => ColorType::PaletteIndex,
+                4 => ColorType::GreyscaleAlpha,
+                6 => ColorType::RGBA,
+                _ => {
+                    pngerr!("invalid color type");
+                }
+            },
+            // ignore filter and compression methods since there is only one
+            interlace_method: data[12] == 1,
+        };
+
+        Ok(idhr)
+    }
+}
+
+/// pHYs Chunk - RFC 2083 (section 4.2.4)
+#[derive(Debug, Clone, Copy)]
+struct PHYSData {
+    /// pixels per unit, X axis
+    x_ppu: u32,
+
+    /// pixels per unit, Y axis
+    y_ppu: u32,
+
+    /// whether the unit is the metre, otherwise it is unknown and the values
+    /// only define the pixel aspect ratio
+    unit_is_meter: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+
+    /// A chunk of `kind` holding `data`, with its length and CRC
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut crc = Crc::new();
+        crc.update(kind);
+        crc.update(data);
+
+        let mut bytes = (data.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(kind);
+        bytes.extend(data);
+        bytes.extend(crc.sum().to_be_bytes());
+        bytes
+    }
+
+    fn ihdr(width: u32, height: u32, bit_depth: u8, color_type: u8, interlaced: bool) -> Vec<u8> {
+        let mut data = width.to_be_bytes().to_vec();
+        data.extend(height.to_be_bytes());
+        data.extend([bit_depth, color_type, 0, 0, interlaced as u8]);
+        chunk(b"IHDR", &data)
+    }
+
+    /// An IDAT chunk compressing `filtered`, the scanlines with their filter
+    /// type bytes
+    fn idat(filtered: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(filtered).unwrap();
+        chunk(b"IDAT", &encoder.finish().unwrap())
+    }
+
+    /// The signature followed by `chunks`
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = PNG_HDR.to_vec();
+        chunks.iter().for_each(|chunk| bytes.extend(chunk));
+        bytes
+    }
+
+    /// A PNG of just the IHDR, one IDAT, and IEND chunks
+    fn simple_png(
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+        color_type: u8,
+        interlaced: bool,
+        filtered: &[u8],
+    ) -> Vec<u8> {
+        png(&[
+            ihdr(width, height, bit_depth, color_type, interlaced),
+            idat(filtered),
+            chunk(b"IEND", &[]),
+        ])
+    }
+
+    /// `len` bytes that look random
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Unfiltered scanlines of `stride` bytes each, every one given filter
+    /// type None
+    fn unfiltered(raw: &[u8], stride: usize) -> Vec<u8> {
+        let mut filtered = Vec::new();
+        for row in raw.chunks(stride) {
+            filtered.push(0);
+            filtered.extend(row);
+        }
+        filtered
+    }
+
+    /// The Adam7 passes over the `width`x`height` image `raw` in `format`,
+    /// each scanline given filter type None
+    fn interlace(raw: &[u8], width: usize, height: usize, format: PixelFormat) -> Vec<u8> {
+        let bits = format.bits_per_pixel();
+        let stride = format.stride(width);
+        let mut filtered = Vec::new();
+        for pass in ADAM7 {
+            let (x0, y0, dx, dy) = pass;
+            let (pass_width, pass_height) = pass_dimensions(width, height, pass);
+            if pass_width == 0 {
+                continue;
+            }
+
+            for y in 0..pass_height {
+                let src = &raw[(y0 + y * dy) * stride..];
+                let mut row = vec![0u8; format.stride(pass_width)];
+                for x in 0..pass_width {
+                    copy_pixel(src, x0 + x * dx, &mut row, x, bits);
+                }
+                filtered.push(0);
+                filtered.extend(row);
+            }
+        }
+        filtered
+    }
+
+    fn decode_with(bytes: &[u8], opts: &DecodeOptions) -> io::Result<Image> {
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from_bytes(bytes.to_vec(), opts)?;
+        let mut image = Image::decode_chunks(&mut chunks, opts, diagnostics, &mut |_| {})?;
+        image.finish(opts, diagnostics)?;
+        Ok(image)
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Image> {
+        decode_with(bytes, &DecodeOptions::default())
+    }
+
+    /// The art of the PNG in `bytes` decoded with the default options
+    fn img(bytes: &[u8]) -> Img {
+        Img::from_parts(None, decode(bytes).unwrap(), LumaMethod::default())
+    }
+
+    /// A 5x3 RGB image of Paeth filtered scanlines with a tEXt chunk
+    fn sample_png() -> Vec<u8> {
+        let mut filtered = Vec::new();
+        for row in 0..3u8 {
+            filtered.push(4);
+            filtered.extend((0..15).map(|idx| row * 40 + idx * 7));
+        }
+        png(&[
+            ihdr(5, 3, 8, 2, false),
+            chunk(b"tEXt", b"Title\0sample"),
+            idat(&filtered),
+            chunk(b"IEND", &[]),
+        ])
+    }
+
+    #[test]
+    fn files_that_are_not_pngs_are_rejected() {
+        let jpeg = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00,
+        ];
+        for bytes in [&PNG_HDR[..3], &jpeg[..]] {
+            let err = decode(bytes).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "invalid PNG signature");
+            assert!(Image::from_unseekable_reader(bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn interlaced_images_decode_like_their_progressive_twin() {
+        // each color type with its IHDR code
+        let formats = [
+            (ColorType::Greyscale, 0, 1),
+            (ColorType::Greyscale, 0, 2),
+            (ColorType::Greyscale, 0, 4),
+            (ColorType::Greyscale, 0, 8),
+            (ColorType::Greyscale, 0, 16),
+            (ColorType::RGB, 2, 8),
+            (ColorType::GreyscaleAlpha, 4, 8),
+            (ColorType::RGBA, 6, 16),
+        ];
+        let sizes = [(1, 1), (2, 9), (3, 5), (8, 8), (13, 7), (33, 17)];
+        for (color_type, code, bit_depth) in formats {
+            let format = PixelFormat::new(color_type, bit_depth).unwrap();
+            for (width, height) in sizes {
+                let stride = format.stride(width);
+                let raw = noise(stride * height, (width * height) as u32);
+                let (w, h) = (width as u32, height as u32);
+                let progressive =
+                    simple_png(w, h, bit_depth, code, false, &unfiltered(&raw, stride));
+                let interlaced = interlace(&raw, width, height, format);
+                let interlaced = simple_png(w, h, bit_depth, code, true, &interlaced);
+
+                let opts = DecodeOptions {
+                    verify_filters: true,
+                    ..Default::default()
+                };
+                let expected = decode_with(&progressive, &opts).unwrap();
+                let image = decode_with(&interlaced, &opts).unwrap();
+                assert_eq!(
+                    image.data, expected.data,
+                    "{:?} {}x{}",
+                    format, width, height
+                );
+                assert_eq!(expected.verify_roundtrip(), Some(true));
+                assert_eq!(image.verify_roundtrip(), Some(true));
+                assert_eq!(decode(&interlaced).unwrap().verify_roundtrip(), None);
+            }
+        }
+    }
+
+    /// Reconstructs `rows` filtered scanlines of `width` bytes each,
+    /// including the filter type byte
+    fn unfilter_rows(filtered: &[u8], width: usize, rows: usize, bpp: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        unfilter(filtered, width, rows, bpp, false, &mut raw).unwrap();
+        raw
+    }
+
+    #[test]
+    fn sub_filter_adds_the_pixel_to_the_left() {
+        let filtered = [1, 10, 20, 251, 230];
+        assert_eq!(unfilter_rows(&filtered, 5, 1, 1), [10, 30, 25, 255]);
+
+        // the pixel to the left is a whole pixel back, not a byte
+        let filtered = [1, 10, 20, 30, 5, 5, 5];
+        assert_eq!(unfilter_rows(&filtered, 7, 1, 3), [10, 20, 30, 15, 25, 35]);
+    }
+
+    #[test]
+    fn up_filter_adds_the_pixel_above() {
+        // the first scanline has a row of zeros above it
+        let filtered = [2, 10, 200, 30, 2, 5, 156, 0];
+        assert_eq!(
+            unfilter_rows(&filtered, 4, 2, 1),
+            [10, 200, 30, 15, 100, 30]
+        );
+    }
+
+    #[test]
+    fn average_filter_adds_the_mean_of_left_and_above() {
+        // the mean of 255 and 100 must not overflow a byte
+        let filtered = [3, 10, 45, 75, 3, 15, 220, 79];
+        assert_eq!(unfilter_rows(&filtered, 4, 2, 1), [10, 50, 100, 20, 255, 0]);
+    }
+
+    #[test]
+    fn paeth_filter_adds_the_closest_neighbour() {
+        let filtered = [0, 10, 50, 90, 20, 4, 20, 246, 110, 191];
+        assert_eq!(
+            unfilter_rows(&filtered, 5, 2, 1),
+            [10, 50, 90, 20, 30, 40, 200, 25]
+        );
+
+        // ties go to the left pixel before the upper left one
+        let filtered = [0, 20, 10, 4, 20, 59];
+        assert_eq!(unfilter_rows(&filtered, 3, 2, 1), [20, 10, 40, 99]);
+    }
+
+    #[test]
+    fn zero_dimensions_are_rejected() {
+        for (width, height) in [(4, 0), (0, 4), (0, 0)] {
+            let bytes = simple_png(width, height, 8, 0, false, &[]);
+            let err = validate(&bytes, &DecodeOptions::default()).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "image dimensions must not be zero, IHDR gives {}x{}",
+                    width, height
+                )
+            );
+            assert!(decode(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn pure_blue_is_dark_under_weighted_luma() {
+        let bytes = simple_png(1, 1, 8, 2, false, &[0, 0, 0, 255]);
+        let methods = [
+            (LumaMethod::Average, 85),
+            (LumaMethod::Rec601, 29),
+            (LumaMethod::Rec709, 18),
+        ];
+        for (method, expected) in methods {
+            assert_eq!(method.luma(0, 0, 255), expected, "{:?}", method);
+            assert_eq!(method.luma(255, 255, 255), 255, "{:?}", method);
+
+            let img = Img::from_parts(None, decode(&bytes).unwrap(), method);
+            assert_eq!(img.grid()[0][0], widen(expected), "{:?}", method);
+        }
+        assert_eq!(LumaMethod::default(), LumaMethod::Rec601);
+    }
+
+    #[test]
+    fn out_of_range_trns_and_bkgd_greys_are_rejected() {
+        for kind in [b"tRNS", b"bKGD"] {
+            let bytes = png(&[
+                ihdr(4, 1, 1, 0, false),
+                chunk(kind, &1000u16.to_be_bytes()),
+                idat(&[0, 0b1010_0000]),
+                chunk(b"IEND", &[]),
+            ]);
+            assert!(validate(&bytes, &DecodeOptions::default()).is_err());
+            assert!(decode(&bytes).is_err());
+
+            let opts = DecodeOptions {
+                lenient: true,
+                ..Default::default()
+            };
+            let image = decode_with(&bytes, &opts).unwrap();
+            assert!(image.transparancy.is_none() && image.background.is_none());
+            assert_eq!(image.data, [255, 0, 255, 0]);
+        }
+    }
+
+    #[test]
+    fn streamed_chunk_lengths_are_not_trusted() {
+        for len in [0xF000_0000u32, MAX_CHUNK_LEN as u32] {
+            let mut bytes = PNG_HDR.to_vec();
+            bytes.extend(len.to_be_bytes());
+            bytes.extend(b"tEXt");
+
+            let start = Instant::now();
+            assert!(Image::from_unseekable_reader(&bytes[..]).is_err());
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn misplaced_ihdr_and_missing_idat_are_rejected() {
+        let text = chunk(b"tEXt", b"Title\0sample");
+        let files = [
+            (
+                png(&[
+                    text,
+                    ihdr(1, 1, 8, 0, false),
+                    idat(&[0, 0]),
+                    chunk(b"IEND", &[]),
+                ]),
+                "IHDR chunk must come first",
+            ),
+            (
+                png(&[
+                    ihdr(1, 1, 8, 0, false),
+                    ihdr(2, 1, 8, 0, false),
+                    idat(&[0, 0]),
+                    chunk(b"IEND", &[]),
+                ]),
+                "PNG must not have more than one IHDR chunk",
+            ),
+            (
+                png(&[ihdr(1, 1, 8, 0, false), chunk(b"IEND", &[])]),
+                "PNG has no IDAT chunk",
+            ),
+        ];
+        for (bytes, message) in files {
+            let err = validate(&bytes, &DecodeOptions::default()).unwrap_err();
+            assert_eq!(err.to_string(), message);
+            assert!(decode(&bytes).is_err());
+
+            let err = Image::from_unseekable_reader(&bytes[..]).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_trns_matches_all_sixteen_bits() {
+        let bytes = png(&[
+            ihdr(2, 1, 16, 0, false),
+            chunk(b"tRNS", &0x1234u16.to_be_bytes()),
+            idat(&[0, 0x12, 0x34, 0x12, 0xFF]),
+            chunk(b"IEND", &[]),
+        ]);
+        let image = decode(&bytes).unwrap();
+        assert_eq!(
+            image.to_rgba8(),
+            [[0x12, 0x12, 0x12, 0], [0x12, 0x12, 0x12, 255]]
+        );
+
+        let bytes = png(&[
+            ihdr(2, 1, 16, 2, false),
+            chunk(b"tRNS", &[0x12, 0x34, 0, 0, 0xFF, 0xFF]),
+            idat(&[
+                0, 0x12, 0x34, 0, 0, 0xFF, 0xFF, 0x12, 0x34, 0, 1, 0xFF, 0xFF,
+            ]),
+            chunk(b"IEND", &[]),
+        ]);
+        let image = decode(&bytes).unwrap();
+        assert_eq!(image.to_rgba8(), [[0x12, 0, 0xFF, 0], [0x12, 0, 0xFF, 255]]);
+    }
+
+    #[test]
+    fn img_can_be_shared_across_threads() {
+        fn shareable<T: Send + Sync + std::panic::RefUnwindSafe>() {}
+        shareable::<Img>();
+    }
+
+    #[test]
+    fn decode_into_keeps_the_buffer_capacity() {
+        let raw = noise(9 * 7, 7);
+        let format = PixelFormat::new(ColorType::Greyscale, 8).unwrap();
+        for interlaced in [false, true] {
+            let filtered = if interlaced {
+                interlace(&raw, 9, 7, format)
+            } else {
+                unfiltered(&raw, 9)
+            };
+            let bytes = simple_png(9, 7, 8, 0, interlaced, &filtered);
+
+            let mut buf = Vec::with_capacity(1 << 16);
+            Image::decode_into(&bytes, &mut buf).unwrap();
+            assert_eq!(buf, raw);
+            assert!(buf.capacity() >= 1 << 16);
+        }
+    }
+
+    #[test]
+    fn estimated_bytes_counts_unpacked_samples() {
+        let header = IHDRData::read(&simple_png(4, 4, 1, 0, false, &[])).unwrap();
+        let image = decode(&simple_png(4, 4, 1, 0, false, &[0; 8])).unwrap();
+        let img = Img::from_parts(None, image, LumaMethod::default());
+        let grid = img.grid().iter().map(|row| row.len() * 2).sum::<usize>();
+        assert_eq!(
+            Image::estimated_bytes(&header),
+            (img.image().data.len() + grid) as u64
+        );
+        assert_eq!(Image::estimated_bytes(&header), 48);
+    }
+
+    #[test]
+    fn streaming_matches_render_for_row_options() {
+        let bytes = sample_png();
+        let img = img(&bytes);
+        let variants = [
+            RenderOptions::default(),
+            RenderOptions {
+                border: true,
+                ..Default::default()
+            },
+            RenderOptions {
+                trim_trailing_spaces: true,
+                ..Default::default()
+            },
+            RenderOptions {
+                border: true,
+                char_aspect_double: true,
+                size: Size::Exact(4, 3),
+                ..Default::default()
+            },
+            RenderOptions {
+                border: true,
+                full_width_cells: true,
+                mirror: true,
+                ..Default::default()
+            },
+        ];
+        for opts in variants {
+            let mut rendered = Vec::new();
+            let expected = img.render(&mut rendered, &opts).unwrap();
+            let mut streamed = Vec::new();
+            let dimensions = Image::render_streaming(&bytes[..], &mut streamed, &opts).unwrap();
+            assert_eq!(
+                String::from_utf8(streamed).unwrap(),
+                String::from_utf8(rendered).unwrap(),
+                "{:?}",
+                opts
+            );
+            assert_eq!(dimensions, expected, "{:?}", opts);
+        }
+    }
+
+    #[test]
+    fn canvas_keeps_its_width_for_wide_characters() {
+        let img = img(&sample_png());
+        let variants = [
+            RenderOptions::default(),
+            RenderOptions {
+                char_aspect_double: true,
+                ..Default::default()
+            },
+            RenderOptions {
+                full_width_cells: true,
+                ..Default::default()
+            },
+        ];
+        for opts in variants {
+            let mut canvas = Vec::new();
+            img.render_canvas(&mut canvas, &opts, 20, 10).unwrap();
+            let canvas = String::from_utf8(canvas).unwrap();
+            assert_eq!(canvas.lines().count(), 10);
+            for line in canvas.lines() {
+                let cells: usize = line
+                    .chars()
+                    .map(|ch| if ch.is_ascii() { 1 } else { 2 })
+                    .sum();
+                assert_eq!(cells, 20, "{:?}", opts);
+            }
+        }
+    }
+
+    #[test]
+    fn cell_aspect_widens_the_art() {
+        let square = RenderOptions {
+            cell_aspect: Some(1.0),
+            size: Size::Height(10),
+            ..Default::default()
+        };
+        assert_eq!(square.dimensions(40, 20), (20, 10));
+        assert_eq!(square.dimensions(40, 20), square.size.dimensions(40, 20));
+
+        let tall = RenderOptions {
+            cell_aspect: Some(2.0),
+            ..square.clone()
+        };
+        assert_eq!(tall.dimensions(40, 20), (40, 10));
+
+        let unset = RenderOptions {
+            cell_aspect: None,
+            ..square.clone()
+        };
+        assert_eq!(unset.dimensions(40, 20), tall.dimensions(40, 20));
+
+        let doubled = RenderOptions {
+            char_aspect_double: true,
+            ..unset.clone()
+        };
+        assert_eq!(doubled.dimensions(40, 20), square.dimensions(40, 20));
+    }
+
+    #[test]
+    fn truncated_files_never_panic() {
+        let bytes = sample_png();
+        decode(&bytes).unwrap();
+
+        for len in 0..bytes.len() {
+            // everything before the IEND chunk is needed
+            let truncated = &bytes[..len];
+            if len < bytes.len() - 12 {
+                assert!(decode(truncated).is_err(), "{} bytes decoded", len);
+            }
+            let _ = decode_with(
+                truncated,
+                &DecodeOptions {
+                    lenient: true,
+                    ..Default::default()
+                },
+            );
+            let _ = validate(truncated, &DecodeOptions::default());
+            let _ = Image::from_unseekable_reader(truncated);
+            let _ = Image::render_streaming(truncated, &mut io::sink(), &RenderOptions::default());
+        }
+    }
+
+    /// A `width`x`height` image whose samples are given by `sample(x, y, c)`,
+    /// as unfiltered scanlines of `bytes` per sample
+    fn gradient(
+        width: usize,
+        height: usize,
+        channels: usize,
+        bytes: usize,
+        sample: impl Fn(usize, usize, usize) -> u16,
+    ) -> Vec<u8> {
+        let mut filtered = Vec::new();
+        for y in 0..height {
+            filtered.push(0);
+            for x in 0..width {
+                for c in 0..channels {
+                    let value = sample(x, y, c).to_be_bytes();
+                    filtered.extend(&value[2 - bytes..]);
+                }
+            }
+        }
+        filtered
+    }
+
+    #[test]
+    fn sixteen_bit_greyscale_renders_like_its_eight_bit_downconversion() {
+        let sample = |x: usize, y: usize, _| (x * 1031 + y * 97) as u16;
+        let deep = gradient(64, 4, 1, 2, sample);
+        let deep = simple_png(64, 4, 16, 0, false, &deep);
+        let shallow = gradient(64, 4, 1, 1, |x, y, c| sample(x, y, c) >> 8);
+        let shallow = simple_png(64, 4, 8, 0, false, &shallow);
+
+        assert_eq!(decode(&deep).unwrap().data, decode(&shallow).unwrap().data);
+        let opts = RenderOptions::default();
+        assert_eq!(
+            img(&deep).to_ascii_string(&opts),
+            img(&shallow).to_ascii_string(&opts)
+        );
+    }
+
+    #[test]
+    fn sixteen_bit_color_decodes_like_its_eight_bit_downconversion() {
+        // RGB and RGBA with their IHDR codes
+        for (code, channels) in [(2, 3), (6, 4)] {
+            let sample = |x: usize, y: usize, c: usize| (x * 2053 + y * 331 + c * 9001) as u16;
+            let deep = gradient(32, 3, channels, 2, sample);
+            let deep = simple_png(32, 3, 16, code, false, &deep);
+            let shallow = gradient(32, 3, channels, 1, |x, y, c| sample(x, y, c) >> 8);
+            let shallow = simple_png(32, 3, 8, code, false, &shallow);
+
+            let (deep, shallow) = (decode(&deep).unwrap(), decode(&shallow).unwrap());
+            assert_eq!(deep.pixel_format(), shallow.pixel_format());
+            assert_eq!(deep.data, shallow.data);
+            assert_eq!(deep.pixel(5, 1), shallow.pixel(5, 1));
+
+            let expected: Vec<u8> = (0..3)
+                .flat_map(|y| (0..32).flat_map(move |x| (0..channels).map(move |c| (x, y, c))))
+                .map(|(x, y, c)| (sample(x, y, c) >> 8) as u8)
+                .collect();
+            assert_eq!(shallow.data, expected);
+        }
+    }
+
+    #[test]
+    fn four_bit_palette_indices_are_unpacked_per_row() {
+        let palette: Vec<[u8; 3]> = (0..16u8).map(|i| [i * 16, 255 - i * 16, i * 3]).collect();
+        let indices = [[0, 1, 2, 3, 4], [15, 14, 13, 12, 11], [5, 10, 5, 10, 9]];
+
+        // five indices take three bytes, the low nibble of the last byte is
+        // padding
+        let mut filtered = Vec::new();
+        for row in indices {
+            filtered.push(0);
+            filtered.extend([
+                row[0] << 4 | row[1],
+                row[2] << 4 | row[3],
+                row[4] << 4 | 0xF,
+            ]);
+        }
+        let bytes = png(&[
+            ihdr(5, 3, 4, 3, false),
+            chunk(b"PLTE", &palette.concat()),
+            idat(&filtered),
+            chunk(b"IEND", &[]),
+        ]);
+
+        let image = decode(&bytes).unwrap();
+        assert_eq!(image.palette_indices().unwrap(), indices.concat());
+        let reference: Vec<[u8; 4]> = indices
+            .concat()
+            .iter()
+            .map(|idx| {
+                let [r, g, b] = palette[*idx as usize];
+                [r, g, b, 255]
+            })
+            .collect();
+        assert_eq!(image.to_rgba8(), reference);
+
+        let img = img(&bytes);
+        for (y, row) in indices.iter().enumerate() {
+            for (x, idx) in row.iter().enumerate() {
+                let [r, g, b] = palette[*idx as usize];
+                assert_eq!(img.grid()[y][x], widen(luma(r, g, b)));
+            }
+        }
+    }
+
+    /// Decodes leniently, returning the violations recovered from
+    fn decode_lenient(bytes: &[u8]) -> (Image, Vec<Diagnostic>) {
+        let opts = DecodeOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        let diagnostics = &mut Vec::new();
+        let mut chunks = ImageHelper::from_bytes(bytes.to_vec(), &opts).unwrap();
+        let mut image = Image::decode_chunks(&mut chunks, &opts, diagnostics, &mut |_| {}).unwrap();
+        image.finish(&opts, diagnostics).unwrap();
+        (image, std::mem::take(diagnostics))
+    }
+
+    #[test]
+    fn ignored_chunks_are_skipped_but_crc_checked() {
+        let large = chunk(b"ruSt", &vec![7; 1 << 22]);
+        let mut corrupted = large.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+
+        let with_chunk = |unknown: &Vec<u8>| {
+            png(&[
+                ihdr(1, 1, 8, 0, false),
+                unknown.clone(),
+                idat(&[0, 9]),
+                chunk(b"IEND", &[]),
+            ])
+        };
+        let (intact, corrupted) = (with_chunk(&large), with_chunk(&corrupted));
+
+        let start = Instant::now();
+        assert_eq!(decode(&intact).unwrap().data, [9]);
+        assert!(Image::from_unseekable_reader(&intact[..]).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let message = "ruSt chunk has a CRC mismatch";
+        assert_eq!(decode(&corrupted).unwrap_err().to_string(), message);
+        let err = validate(&corrupted, &DecodeOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), message);
+        let err = Image::from_unseekable_reader(&corrupted[..]).unwrap_err();
+        assert_eq!(err.to_string(), message);
+
+        let (image, diagnostics) = decode_lenient(&corrupted);
+        assert_eq!(image.data, [9]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("ruSt"));
+        assert_eq!(diagnostics[0].message, message);
+    }
+
+    #[test]
+    fn legend_lists_every_ramp_character() {
+        let art = img(&sample_png());
+        let opts = RenderOptions {
+            with_legend: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        art.render(&mut out, &opts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let legend = out
+            .lines()
+            .find(|line| RAMP.chars().all(|c| line.contains(c)));
+        assert_eq!(legend, Some(RAMP));
+
+        let mut plain = Vec::new();
+        art.render(&mut plain, &RenderOptions::default()).unwrap();
+        assert!(!String::from_utf8(plain).unwrap().contains(RAMP));
+    }
+
+    #[test]
+    fn every_filter_treats_the_prior_row_of_a_single_row_as_zeros() {
+        let raw = [10, 20, 30, 40, 250, 5];
+        // with a zero prior row Up adds nothing, Average halves the left
+        // pixel and Paeth always predicts the left pixel
+        let rows: [(u8, [u8; 6]); 5] = [
+            (0, raw),
+            (1, [10, 20, 20, 20, 220, 221]),
+            (2, raw),
+            (3, [10, 20, 25, 30, 235, 241]),
+            (4, [10, 20, 20, 20, 220, 221]),
+        ];
+        for (filter_type, row) in rows {
+            let mut filtered = vec![filter_type];
+            filtered.extend_from_slice(&row);
+            assert_eq!(
+                unfilter_rows(&filtered, 7, 1, 2),
+                raw,
+                "filter {}",
+                filter_type
+            );
+        }
+    }
+
+    #[test]
+    fn streaming_uses_the_decode_options_luma() {
+        let bytes = sample_png();
+        let opts = RenderOptions::default();
+        let mut default = Vec::new();
+        Image::render_streaming(&bytes[..], &mut default, &opts).unwrap();
+
+        for luma in [LumaMethod::Average, LumaMethod::Rec601, LumaMethod::Rec709] {
+            let decode_opts = DecodeOptions {
+                luma,
+                ..Default::default()
+            };
+            let art = Img::from_parts(None, decode_with(&bytes, &decode_opts).unwrap(), luma);
+            let mut rendered = Vec::new();
+            art.render(&mut rendered, &opts).unwrap();
+            let mut streamed = Vec::new();
+            Image::render_streaming_with_options(&bytes[..], &mut streamed, &opts, &decode_opts)
+                .unwrap();
+            assert_eq!(streamed, rendered, "{:?}", luma);
+            assert_eq!(
+                streamed == default,
+                luma == LumaMethod::Rec601,
+                "{:?}",
+                luma
+            );
+        }
+    }
+
+    #[test]
+    fn streamed_rows_match_the_buffered_render() {
+        let bytes = sample_png();
+        let mut rows = Vec::new();
+        let dimensions =
+            Image::stream_ascii_rows(&bytes[..], 2, |row| rows.push(row.to_owned())).unwrap();
+        assert_eq!(dimensions, (3, 2));
+
+        let opts = RenderOptions {
+            size: Size::Exact(3, 2),
+            ..Default::default()
+        };
+        let buffered = img(&bytes).to_ascii_string(&opts);
+        assert_eq!(rows, buffered.lines().collect::<Vec<_>>());
+    }
+
+    /// Writes `bytes` to a file of the temporary directory unique to this
+    /// test run, returning its path
+    fn temp_file(name: &str, bytes: &[u8]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("png_to_ascii_{}_{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn streaming_applies_the_decode_options() {
+        let grey16 = gradient(9, 5, 1, 2, |x, y, _| (x * 7001 + y * 301) as u16);
+        let ga16 = gradient(9, 5, 2, 2, |x, y, c| [x * 7001, y * 16000][c] as u16);
+        let ga16 = simple_png(9, 5, 16, 4, false, &ga16);
+        // indices 2 and 3 have no entry in the palette
+        let palette = png(&[
+            ihdr(4, 2, 2, 3, false),
+            chunk(b"PLTE", &[0, 0, 0, 255, 255, 255]),
+            idat(&[0, 0b00011011, 0, 0b11100100]),
+            chunk(b"IEND", &[]),
+        ]);
+        let mut corrupted = sample_png();
+        let text = corrupted.windows(6).position(|w| w == b"sample").unwrap();
+        corrupted[text] ^= 1;
+        let mut prefixed = b"junk".repeat(100);
+        prefixed.extend(sample_png());
+
+        let cases = [
+            (
+                simple_png(9, 5, 16, 0, false, &grey16),
+                DecodeOptions {
+                    precise_16bit: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                ga16.clone(),
+                DecodeOptions {
+                    composite_background: Some([255, 255, 255]),
+                    ..Default::default()
+                },
+            ),
+            (
+                ga16,
+                DecodeOptions {
+                    assume_premultiplied: true,
+                    luma: LumaMethod::Average,
+                    ..Default::default()
+                },
+            ),
+            (
+                palette,
+                DecodeOptions {
+                    lenient: true,
+                    palette_fallback: Some([128, 128, 128]),
+                    ..Default::default()
+                },
+            ),
+            (
+                corrupted,
+                DecodeOptions {
+                    lenient: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                prefixed,
+                DecodeOptions {
+                    scan_for_signature: true,
+                    ..Default::default()
+                },
+            ),
+        ];
+        let opts = RenderOptions::default();
+        for (i, (bytes, decode_opts)) in cases.iter().enumerate() {
+            let path = temp_file(&format!("streaming_options_{}.png", i), bytes);
+            let art = Img::with_options(&path, decode_opts).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let mut rendered = Vec::new();
+            art.render(&mut rendered, &opts).unwrap();
+            let mut streamed = Vec::new();
+            Image::render_streaming_with_options(&bytes[..], &mut streamed, &opts, decode_opts)
+                .unwrap();
+            assert_eq!(streamed, rendered, "{:?}", decode_opts);
+
+            // every case decodes differently without its options
+            let mut plain = Vec::new();
+            let default = Image::render_streaming(&bytes[..], &mut plain, &opts);
+            assert!(default.is_err() || plain != streamed, "{:?}", decode_opts);
+
+            let image =
+                Image::from_unseekable_reader_with_options(&bytes[..], decode_opts).unwrap();
+            assert_eq!(image.data, art.image.data, "{:?}", decode_opts);
+        }
+    }
+
+    #[test]
+    fn streaming_honours_the_chunk_limit() {
+        let bytes = sample_png();
+        let opts = DecodeOptions {
+            max_chunks: Some(2),
+            ..Default::default()
+        };
+        let render = RenderOptions::default();
+        let streamed =
+            Image::render_streaming_with_options(&bytes[..], &mut io::sink(), &render, &opts);
+        assert!(streamed.is_err());
+        assert!(Image::from_unseekable_reader_with_options(&bytes[..], &opts).is_err());
+        assert!(decode_with(&bytes, &opts).is_err());
+
+        let opts = DecodeOptions {
+            max_chunks: Some(4),
+            ..opts
+        };
+        let streamed =
+            Image::render_streaming_with_options(&bytes[..], &mut io::sink(), &render, &opts);
+        assert!(streamed.is_ok());
+        assert!(Image::from_unseekable_reader_with_options(&bytes[..], &opts).is_ok());
+    }
+
+    #[test]
+    fn interlaced_images_stream_like_their_progressive_twin() {
+        let format = PixelFormat::new(ColorType::RGB, 8).unwrap();
+        let (width, height) = (13, 7);
+        let stride = format.stride(width);
+        let raw = noise(stride * height, 7);
+        let progressive = simple_png(13, 7, 8, 2, false, &unfiltered(&raw, stride));
+        let interlaced = simple_png(13, 7, 8, 2, true, &interlace(&raw, width, height, format));
+
+        let opts = RenderOptions::default();
+        let mut expected = Vec::new();
+        img(&progressive).render(&mut expected, &opts).unwrap();
+        let mut streamed = Vec::new();
+        Image::render_streaming(&interlaced[..], &mut streamed, &opts).unwrap();
+        assert_eq!(streamed, expected);
+    }
+
+    /// `text` compressed with zlib
+    fn zlib(text: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn compressed_text_is_cut_short_at_the_cap() {
+        let mut ztxt = b"Comment\0\0".to_vec();
+        ztxt.extend(zlib(&vec![b'a'; MAX_TEXT_LEN * 2]));
+        // two byte characters that the cap splits down the middle
+        let mut itxt = b"Comment\0\x01\0en\0\0".to_vec();
+        let text = format!("a{}", "é".repeat(MAX_TEXT_LEN / 2));
+        itxt.extend(zlib(text.as_bytes()));
+
+        let bytes = png(&[
+            ihdr(1, 1, 8, 0, false),
+            chunk(b"zTXt", &ztxt),
+            chunk(b"iTXt", &itxt),
+            idat(&[0, 0]),
+            chunk(b"IEND", &[]),
+        ]);
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("zTXt"), "{}", err);
+
+        let (image, diagnostics) = decode_lenient(&bytes);
+        let chunks: Vec<_> = diagnostics.iter().map(|d| d.chunk.as_deref()).collect();
+        assert_eq!(chunks, [Some("zTXt"), Some("iTXt")]);
+        assert_eq!(image.text()[0].text.len(), MAX_TEXT_LEN);
+        assert_eq!(image.text()[1].text, text[..MAX_TEXT_LEN - 1]);
+    }
+
+    #[test]
+    fn captions_come_from_the_first_title_or_description() {
+        let bytes = png(&[
+            ihdr(1, 1, 8, 0, false),
+            chunk(b"tEXt", b"Software\0an encoder"),
+            chunk(
+                b"iTXt",
+                "Description\0\0\0de\0Beschreibung\0Grüße & <art>".as_bytes(),
+            ),
+            chunk(b"tEXt", b"Title\0second"),
+            idat(&[0, 0]),
+            chunk(b"IEND", &[]),
+        ]);
+        let art = img(&bytes);
+        let opts = RenderOptions {
+            caption_from_metadata: true,
+            ..Default::default()
+        };
+        let mut html = Vec::new();
+        art.render_html(&mut html, &opts).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<p>Grüße &amp; &lt;art&gt;</p>"), "{}", html);
+        assert!(!html.contains("second"));
+
+        let text = art.to_ascii_string(&opts);
+        assert_eq!(text.lines().last(), Some("Grüße & <art>"));
+
+        let mut html = Vec::new();
+        art.render_html(&mut html, &RenderOptions::default())
+            .unwrap();
+        assert!(!String::from_utf8(html).unwrap().contains("<p>"));
+    }
+
+    #[test]
+    fn fully_transparent_images_render_as_the_placeholder() {
+        // a 1x1 greyscale alpha pixel and a 3x2 RGBA image, all transparent
+        let images = [
+            simple_png(1, 1, 8, 4, false, &[0, 200, 0]),
+            simple_png(3, 2, 8, 6, false, &unfiltered(&[90, 0, 0, 0].repeat(6), 12)),
+        ];
+        for bytes in images {
+            let art = img(&bytes);
+            assert_eq!(art.to_ascii_string(&RenderOptions::default()), " \n");
+
+            let opts = RenderOptions {
+                transparent_placeholder: Some('.'),
+                size: Size::Height(10),
+                ..Default::default()
+            };
+            assert_eq!(art.to_ascii_string(&opts), ".\n");
+
+            let opts = RenderOptions {
+                transparent_char: Some('#'),
+                trim_trailing_spaces: true,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            assert_eq!(art.render(&mut out, &opts).unwrap(), (1, 1));
+            assert_eq!(out, b" \n");
+        }
+    }
+
+    #[test]
+    fn heatmap_blocks_stand_for_the_art_characters() {
+        let art = img(&sample_png());
+        let heatmap = |opts: &RenderOptions| {
+            let mut map = Vec::new();
+            let dimensions = art.render_heatmap(&mut map, opts).unwrap();
+            assert_eq!(dimensions, art.render(&mut io::sink(), opts).unwrap());
+            let map = String::from_utf8(map).unwrap();
+            for line in map.lines() {
+                assert_eq!(line.matches('█').count(), dimensions.0, "{:?}", opts);
+            }
+            assert_eq!(map.lines().count(), dimensions.1, "{:?}", opts);
+            map
+        };
+
+        let opts = RenderOptions {
+            size: Size::Exact(4, 2),
+            ..Default::default()
+        };
+        let plain = heatmap(&opts);
+        let averaged = heatmap(&RenderOptions {
+            block_average: Some(Rounding::Nearest),
+            ..opts.clone()
+        });
+        let equalized = heatmap(&RenderOptions {
+            adaptive_tile: Some(2),
+            ..opts.clone()
+        });
+        assert_ne!(plain, averaged);
+        assert_ne!(plain, equalized);
+    }
+
+    #[test]
+    fn progress_rises_to_one_hundred_percent() {
+        let (width, height) = (512, 512);
+        let filtered = unfiltered(&noise(width * height, 3), width);
+        let bytes = simple_png(512, 512, 8, 0, false, &filtered);
+        let path = temp_file("progress.png", &bytes);
+
+        let mut reported = Vec::new();
+        let art = Img::with_progress(&path, &DecodeOptions::default(), |percent| {
+            reported.push(percent)
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reported.len() > 2, "{:?}", reported);
+        assert!(
+            reported.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            reported
+        );
+        assert_eq!(reported.first(), Some(&0));
+        assert_eq!(reported.last(), Some(&100));
+        assert_eq!(art.image.data.len(), width * height);
+    }
+
+    #[test]
+    fn saturating_filters_leave_valid_images_alone() {
+        // brightening rightwards and downwards, so no filter needs to wrap
+        let width = 16;
+        let mut filtered = Vec::new();
+        for y in 0..8 {
+            let row: Vec<u8> = (0..width).map(|x| (x * 9 + y * 13) as u8).collect();
+            filtered.push(y as u8 % 5);
+            filtered.extend(row.iter().enumerate().map(|(x, raw)| {
+                let left = if x > 0 { row[x - 1] } else { 0 };
+                let above = if y > 0 { raw - 13 } else { 0 };
+                let upper_left = if x > 0 && y > 0 { left - 13 } else { 0 };
+                match y % 5 {
+                    0 => *raw,
+                    1 => raw - left,
+                    2 => raw - above,
+                    3 => raw - ((left as u16 + above as u16) / 2) as u8,
+                    _ => raw - paeth_predictor(left, above, upper_left),
+                }
+            }));
+        }
+        let bytes = simple_png(16, 8, 8, 0, false, &filtered);
+        let path = temp_file("saturating.png", &bytes);
+        let opts = DecodeOptions {
+            saturating_filters: true,
+            ..Default::default()
+        };
+        let (image, stats) = Image::from_with_stats(&path, &opts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stats.filter_clamps, 0);
+        assert_eq!(image.data, decode(&bytes).unwrap().data);
+
+        // garbage Sub scanlines overshoot their predictions
+        let mut corrupt = vec![1];
+        corrupt.extend([200; 16]);
+        let bytes = simple_png(16, 1, 8, 0, false, &corrupt);
+        let path = temp_file("saturating_corrupt.png", &bytes);
+        let (_, stats) = Image::from_with_stats(&path, &opts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(stats.filter_clamps > 0);
+    }
+
+    #[test]
+    fn compositing_blends_alpha_channels_and_color_keys() {
+        let over_white = DecodeOptions {
+            composite_background: Some([255, 255, 255]),
+            ..Default::default()
+        };
+
+        // black at 50% alpha over white is mid-grey
+        let half = simple_png(1, 1, 16, 4, false, &[0, 0, 0, 0x80, 0x00]);
+        let image = decode_with(&half, &over_white).unwrap();
+        assert_eq!(image.color_type, ColorType::Greyscale);
+        assert_eq!(image.data, [127]);
+
+        // keyed pixels turn into the background at every bit depth
+        let keyed = [
+            png(&[
+                ihdr(2, 1, 8, 0, false),
+                chunk(b"tRNS", &[0, 40]),
+                idat(&[0, 40, 41]),
+                chunk(b"IEND", &[]),
+            ]),
+            png(&[
+                ihdr(2, 1, 2, 0, false),
+                chunk(b"tRNS", &[0, 1]),
+                idat(&[0, 0b0110_0000]),
+                chunk(b"IEND", &[]),
+            ]),
+            png(&[
+                ihdr(2, 1, 16, 2, false),
+                chunk(b"tRNS", &[0x12, 0x34, 0, 0, 0, 0]),
+                idat(&[0, 0x12, 0x34, 0, 0, 0, 0, 0x12, 0x35, 0, 0, 0, 0]),
+                chunk(b"IEND", &[]),
+            ]),
+        ];
+        let expected: [&[u8]; 3] = [&[255, 41], &[255, 170], &[255, 255, 255, 0x12, 0, 0]];
+        for (bytes, expected) in keyed.iter().zip(expected) {
+            let image = decode_with(bytes, &over_white).unwrap();
+            assert_eq!(image.data, expected);
+            assert!(image.transparancy.is_none());
+        }
+
+        // palette entries are blended with their tRNS alphas
+        let palette = png(&[
+            ihdr(2, 1, 8, 3, false),
+            chunk(b"PLTE", &[0, 0, 0, 200, 100, 0]),
+            chunk(b"tRNS", &[0, 51]),
+            idat(&[0, 0, 1]),
+            chunk(b"IEND", &[]),
+        ]);
+        let image = decode_with(&palette, &over_white).unwrap();
+        assert_eq!(
+            image.to_rgba8(),
+            [[255, 255, 255, 255], [244, 224, 204, 255]]
+        );
+    }
+
+    /// An eXIf chunk holding just the orientation tag
+    fn exif(orientation: u16, big_endian: bool) -> Vec<u8> {
+        // big-endian fields, reversed for a little-endian TIFF header
+        let fields = [
+            42u16.to_be_bytes().to_vec(),
+            8u32.to_be_bytes().to_vec(),
+            1u16.to_be_bytes().to_vec(),
+            // the orientation, a single SHORT
+            0x0112u16.to_be_bytes().to_vec(),
+            3u16.to_be_bytes().to_vec(),
+            1u32.to_be_bytes().to_vec(),
+            orientation.to_be_bytes().to_vec(),
+            vec![0, 0],
+        ];
+        let mut data = if big_endian {
+            b"MM".to_vec()
+        } else {
+            b"II".to_vec()
+        };
+        for mut field in fields {
+            if !big_endian {
+                field.reverse();
+            }
+            data.extend(field);
+        }
+        chunk(b"eXIf", &data)
+    }
+
+    #[test]
+    fn every_orientation_turns_the_image_upright() {
+        // the stored 3x2 image is 1 2 3 over 4 5 6
+        let expected: [(u32, &[u8]); 8] = [
+            (3, &[1, 2, 3, 4, 5, 6]),
+            (3, &[3, 2, 1, 6, 5, 4]),
+            (3, &[6, 5, 4, 3, 2, 1]),
+            (3, &[4, 5, 6, 1, 2, 3]),
+            (2, &[1, 4, 2, 5, 3, 6]),
+            (2, &[4, 1, 5, 2, 6, 3]),
+            (2, &[6, 3, 5, 2, 4, 1]),
+            (2, &[3, 6, 2, 5, 1, 4]),
+        ];
+        let opts = DecodeOptions {
+            auto_orient: true,
+            ..Default::default()
+        };
+        let mut phys = 1000u32.to_be_bytes().to_vec();
+        phys.extend(2000u32.to_be_bytes());
+        phys.push(1);
+
+        for (orientation, (width, pixels)) in (1..=8).zip(expected) {
+            // 8-bit samples, and 4-bit ones that are remapped while packed
+            let images = [
+                (8, vec![0, 1, 2, 3, 0, 4, 5, 6], 1),
+                (4, vec![0, 0x12, 0x30, 0, 0x45, 0x60], 17),
+            ];
+            for (bit_depth, filtered, scale) in images {
+                let bytes = png(&[
+                    ihdr(3, 2, bit_depth, 0, false),
+                    chunk(b"pHYs", &phys),
+                    exif(orientation, orientation % 2 == 0),
+                    idat(&filtered),
+                    chunk(b"IEND", &[]),
+                ]);
+                let image = decode_with(&bytes, &opts).unwrap();
+                let upright: Vec<u8> = pixels.iter().map(|pixel| pixel * scale).collect();
+                assert_eq!(image.data, upright, "{} at {} bits", orientation, bit_depth);
+                assert_eq!((image.width, image.height), (width, 5 - width));
+                assert_eq!(image.orientation(), Some(1));
+
+                // pixels per unit follow the axes they measure
+                let physical = image.physical.as_ref().unwrap();
+                let ppu = (physical.x_ppu, physical.y_ppu);
+                assert_eq!(
+                    ppu,
+                    if width == 3 {
+                        (1000, 2000)
+                    } else {
+                        (2000, 1000)
+                    }
+                );
+
+                let unoriented = decode(&bytes).unwrap();
+                assert_eq!(unoriented.orientation(), Some(orientation));
+                assert_eq!(unoriented.width, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn interlaced_previews_blow_up_the_first_pass() {
+        let palette = chunk(
+            b"PLTE",
+            &[0, 0, 0, 80, 80, 80, 160, 160, 160, 255, 255, 255],
+        );
+        let formats = [(ColorType::RGB, 2, 8), (ColorType::PaletteIndex, 3, 2)];
+        for (color_type, code, bit_depth) in formats {
+            let format = PixelFormat::new(color_type, bit_depth).unwrap();
+            for (width, height) in [(13, 7), (17, 9)] {
+                let stride = format.stride(width);
+                let raw = noise(stride * height, (width * height) as u32);
+                let (w, h) = (width as u32, height as u32);
+                let progressive = unfiltered(&raw, stride);
+                let interlaced = interlace(&raw, width, height, format);
+                let file = |filtered: &[u8], interlaced| {
+                    png(&[
+                        ihdr(w, h, bit_depth, code, interlaced),
+                        palette.clone(),
+                        idat(filtered),
+                        chunk(b"IEND", &[]),
+                    ])
+                };
+                let full = decode(&file(&progressive, false)).unwrap();
+                let path = temp_file(
+                    &format!("preview_{}_{}x{}", code, width, height),
+                    &file(&interlaced, true),
+                );
+                let preview = Image::from_interlaced_preview(&path).unwrap();
+                std::fs::remove_file(&path).unwrap();
+
+                // every pixel takes the pass 1 pixel at the corner of its 8x8 block
+                assert_eq!((preview.width, preview.height), (w, h));
+                let bytes = full.data.len() / (width * height);
+                let expected: Vec<u8> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x / 8 * 8, y / 8 * 8)))
+                    .flat_map(|(x, y)| {
+                        let at = (y * width + x) * bytes;
+                        full.data[at..at + bytes].to_vec()
+                    })
+                    .collect();
+                assert_eq!(preview.data, expected, "{:?} {}x{}", format, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn threads_sharing_one_art_draw_it_alike() {
+        let format = PixelFormat::new(ColorType::RGBA, 8).unwrap();
+        let stride = format.stride(40);
+        let bytes = simple_png(
+            40,
+            30,
+            8,
+            6,
+            false,
+            &unfiltered(&noise(stride * 30, 3), stride),
+        );
+        let opts = RenderOptions::default();
+        let expected = img(&bytes).to_ascii_string(&opts);
+
+        // the grid and alpha plane are built by whichever thread gets there first
+        let art = img(&bytes);
+        let rendered: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| art.to_ascii_string(&opts)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(rendered.iter().all(|art| *art == expected));
+    }
+
+    #[test]
+    fn cache_decodes_again_only_once_the_file_changes() {
+        let black = simple_png(2, 1, 8, 0, false, &[0, 0, 0]);
+        let white = simple_png(2, 1, 8, 0, false, &[0, 255, 255]);
+        let path = temp_file("cache.png", &black);
+        let mut cache = ImageCache::new();
+
+        assert_eq!(cache.get(&path).unwrap().data, [0, 0]);
+        assert_eq!(cache.get(&path).unwrap().data, [0, 0]);
+        assert_eq!((cache.hits(), cache.len()), (1, 1));
+
+        // a later modification time however coarse the file system's clock
+        std::fs::write(&path, &white).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        file.set_modified(later).unwrap();
+        assert_eq!(cache.get(&path).unwrap().data, [255, 255]);
+        assert_eq!((cache.hits(), cache.len()), (1, 1));
+
+        cache.invalidate(&path);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&path).unwrap().data, [255, 255]);
+        assert_eq!((cache.hits(), cache.len()), (1, 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_palette_indices_fall_back_in_lenient_mode() {
+        // indices 2 and 3 of the 2-bit data have no entry in the palette
+        let bytes = png(&[
+            ihdr(4, 1, 2, 3, false),
+            chunk(b"PLTE", &[0, 0, 0, 255, 255, 255]),
+            idat(&[0, 0b0001_1011]),
+            chunk(b"IEND", &[]),
+        ]);
+        assert!(decode(&bytes).is_err());
+
+        let (image, diagnostics) = decode_lenient(&bytes);
+        assert_eq!(diagnostics.len(), 1);
+        let pixels = image.to_rgba8();
+        assert_eq!(
+            pixels,
+            [[0, 0, 0, 255], [255, 255, 255, 255], [255; 4], [255; 4]]
+        );
+        let art = Img::from_parts(None, image, LumaMethod::default());
+        assert!(!art.to_ascii_string(&RenderOptions::default()).is_empty());
+
+        let opts = DecodeOptions {
+            lenient: true,
+            palette_fallback: Some([255, 0, 0]),
+            ..Default::default()
+        };
+        let pixels = decode_with(&bytes, &opts).unwrap().to_rgba8();
+        assert_eq!(pixels[2..], [[255, 0, 0, 255], [255, 0, 0, 255]]);
+    }
+
+    #[test]
+    fn half_black_half_white_averages_to_mid_grey() {
+        let bytes = simple_png(4, 2, 8, 0, false, &[0, 0, 0, 0, 0, 0, 255, 255, 255, 255]);
+        assert_eq!(
+            decode(&bytes).unwrap().average_color(),
+            [127, 127, 127, 255]
+        );
+
+        // the transparent black pixel adds nothing to the color
+        let ga = [0, 255, 255, 255, 255, 0, 0];
+        let bytes = simple_png(3, 1, 8, 4, false, &ga);
+        assert_eq!(
+            decode(&bytes).unwrap().average_color(),
+            [255, 255, 255, 170]
+        );
+    }
+
+    #[test]
+    fn signature_scans_skip_leading_junk() {
+        let image = simple_png(2, 1, 8, 0, false, &[0, 10, 20]);
+        let mut bom = b"\xEF\xBB\xBF".to_vec();
+        bom.extend(&image);
+        let opts = DecodeOptions {
+            scan_for_signature: true,
+            ..Default::default()
+        };
+        assert!(decode(&bom).is_err());
+        assert_eq!(decode_with(&bom, &opts).unwrap().data, [10, 20]);
+
+        // the scan gives up a few KB in
+        let mut buried = vec![b' '; SIGNATURE_SCAN_LIMIT];
+        buried.extend(&image);
+        assert!(decode_with(&buried, &opts).is_err());
+    }
+
+    #[test]
+    fn svg_has_a_text_element_per_row() {
+        let grey = gradient(12, 6, 1, 1, |x, _, _| (x * 20) as u16);
+        let art = img(&simple_png(12, 6, 8, 0, false, &grey));
+        let opts = RenderOptions::default();
+        let rows = art.ascii_rows(&opts);
+        let mut out = Vec::new();
+        let (cols, lines) = art.render_svg(&mut out, &opts, 10).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+
+        assert_eq!(lines, rows.len());
+        assert!(cols > 0);
+        assert!(
+            svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""),
+            "{}",
+            svg
+        );
+        assert!(svg.contains("font-family=\"monospace\" font-size=\"10\""));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        let texts: Vec<_> = svg
+            .lines()
+            .filter(|line| line.starts_with("<text "))
+            .collect();
+        assert_eq!(texts.len(), rows.len());
+        for (text, row) in texts.iter().zip(&rows) {
+            assert!(text.ends_with(&format!(">{}</text>", escape_xml(row))));
+        }
+    }
+
+    #[test]
+    fn physical_size_follows_asymmetric_phys() {
+        // 100 DPI across and 50 DPI down, in pixels per metre
+        let mut phys = 3937u32.to_be_bytes().to_vec();
+        phys.extend(1969u32.to_be_bytes());
+        phys.push(1);
+        let grey = gradient(100, 100, 1, 1, |x, y, _| (x + y) as u16);
+        let bytes = png(&[
+            ihdr(100, 100, 8, 0, false),
+            chunk(b"pHYs", &phys),
+            idat(&grey),
+            chunk(b"IEND", &[]),
+        ]);
+        assert_eq!(img(&bytes).physical_size(10.0), Size::Exact(10, 20));
+
+        // square 72 DPI pixels without the chunk
+        let bytes = simple_png(100, 100, 8, 0, false, &grey);
+        assert_eq!(img(&bytes).physical_size(10.0), Size::Exact(14, 14));
+    }
+
+    /// The strings of the JSON array starting at the `[` of `json`
+    fn parse_json_strings(json: &str) -> Vec<String> {
+        let mut strings = Vec::new();
+        let mut chars = json.chars();
+        assert_eq!(chars.next(), Some('['));
+        loop {
+            match chars.next() {
+                Some(']') => return strings,
+                Some(',') => {}
+                Some('"') => {
+                    let mut string = String::new();
+                    loop {
+                        match chars.next().unwrap() {
+                            '"' => break,
+                            '\\' => match chars.next().unwrap() {
+                                'n' => string.push('\n'),
+                                'u' => {
+                                    let hex: String = chars.by_ref().take(4).collect();
+                                    let code = u32::from_str_radix(&hex, 16).unwrap();
+                                    string.push(char::from_u32(code).unwrap());
+                                }
+                                c => string.push(c),
+                            },
+                            c => string.push(c),
+                        }
+                    }
+                    strings.push(string);
+                }
+                other => panic!("unexpected {:?} in {}", other, json),
+            }
+        }
+    }
+
+    #[test]
+    fn json_rows_parse_back_to_the_rendered_art() {
+        // the full ramp, quotes and backslashes included
+        let grey = gradient(66, 2, 1, 1, |x, _, _| (x * 255 / 65) as u16);
+        let art = img(&simple_png(66, 2, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(66, 2),
+            ..Default::default()
+        };
+        let json = art.to_json(&opts);
+
+        let (head, rows) = json.split_at(json.find('[').unwrap());
+        assert_eq!(head, "{\"width\":66,\"height\":2,\"rows\":");
+        assert!(rows.ends_with("]}"));
+        let rows = parse_json_strings(rows);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows, art.ascii_rows(&opts));
+        assert!(rows[0].contains('"') && rows[0].contains('\\'));
+    }
+
+    #[test]
+    fn two_color_images_have_two_unique_colors() {
+        let rgb = [0, 255, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0, 255, 0, 0, 255];
+        let bytes = simple_png(5, 1, 8, 2, false, &rgb);
+        assert_eq!(decode(&bytes).unwrap().unique_color_count(), 2);
+
+        // every pixel a different color, more than are counted
+        let rgb = gradient(300, 300, 3, 1, |x, y, c| [x % 256, y, x / 256][c] as u16);
+        let bytes = simple_png(300, 300, 8, 2, false, &rgb);
+        assert_eq!(
+            decode(&bytes).unwrap().unique_color_count(),
+            MAX_UNIQUE_COLORS
+        );
+    }
+
+    #[test]
+    fn crc_mismatches_are_diagnosed_by_lenient_decodes() {
+        let mut bytes = png(&[
+            ihdr(2, 1, 8, 0, false),
+            chunk(b"tEXt", b"Title\0mangled"),
+            idat(&[0, 10, 20]),
+            chunk(b"IEND", &[]),
+        ]);
+        // the last byte of the tEXt CRC, after the signature, IHDR, and text
+        let crc_end = 8 + 25 + 12 + 13;
+        bytes[crc_end - 1] ^= 0xFF;
+        let path = temp_file("crc_mismatch.png", &bytes);
+
+        assert!(Image::from(&path).is_err());
+        let (image, diagnostics) = Image::from_lenient(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.data, [10, 20]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("tEXt"));
+        assert!(diagnostics[0].message.contains("CRC"), "{}", diagnostics[0]);
+    }
+
+    #[test]
+    fn brightness_adjustments_clamp_to_white() {
+        let bytes = simple_png(1, 1, 8, 0, false, &[0, 128]);
+        let mut art = img(&bytes);
+        art.adjust(50, 1.0, 1.0);
+        assert_eq!(art.grid()[0][0], widen(178));
+        art.adjust(200, 1.0, 1.0);
+        assert_eq!(art.grid()[0][0], widen(255));
+
+        // contrast pulls away from the middle, clamping at black
+        let mut art = img(&bytes);
+        art.adjust(-100, 3.0, 1.0);
+        assert_eq!(art.grid()[0][0], 0);
+    }
+
+    #[test]
+    fn edge_glyphs_follow_the_edge_direction() {
+        const W: u16 = u16::MAX;
+        let glyph = |rows: [[u16; 3]; 3]| edge_glyph([&rows[0], &rows[1], &rows[2]], 1, 64);
+
+        // light below and to the right of a rising diagonal, then its mirror
+        assert_eq!(glyph([[0, 0, W], [0, W, W], [W, W, W]]), Some('/'));
+        assert_eq!(glyph([[W, 0, 0], [W, W, 0], [W, W, W]]), Some('\\'));
+        assert_eq!(glyph([[0, 0, W], [0, 0, W], [0, 0, W]]), Some('|'));
+        assert_eq!(glyph([[0, 0, 0], [0, 0, 0], [W, W, W]]), Some('-'));
+
+        // flat areas and faint edges keep the ramp
+        assert_eq!(glyph([[W; 3]; 3]), None);
+        let faint = W / 32;
+        assert_eq!(glyph([[0, 0, faint], [0, 0, faint], [0, 0, faint]]), None);
+    }
+
+    #[test]
+    fn mappers_replace_the_brightness_formula() {
+        // pure red is dark to the built-in luma but full to the red channel,
+        // and pure blue the other way around
+        let rgb = [0, 255, 0, 0, 0, 0, 255];
+        let path = temp_file("mapper.png", &simple_png(2, 1, 8, 2, false, &rgb));
+        let art = Img::from_with_mapper(&path, |[red, ..]| red).unwrap();
+        let default = Img::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(*art.grid(), [[widen(255), 0]]);
+        assert!(default.grid()[0][0] < widen(128));
+        assert!(default.grid()[0][1] > 0);
+    }
+
+    #[test]
+    fn render_methods_report_the_lines_they_write() {
+        let grey = gradient(16, 12, 1, 1, |x, y, _| (x * 16 + y) as u16);
+        let art = img(&simple_png(16, 12, 8, 0, false, &grey));
+        // the number of lines and of characters in the widest one
+        let measure = |out: Vec<u8>| {
+            let out = String::from_utf8(out).unwrap();
+            let cols = out.lines().map(|line| line.chars().count()).max();
+            (cols.unwrap_or(0), out.lines().count())
+        };
+
+        let opts = RenderOptions {
+            size: Size::Exact(20, 7),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(art.render(&mut out, &opts).unwrap(), (20, 7));
+        assert_eq!(measure(out), (20, 7));
+
+        let mut out = Vec::new();
+        let dimensions = art.render_canvas(&mut out, &opts, 30, 9).unwrap();
+        assert_eq!(dimensions, (30, 9));
+        assert_eq!(measure(out), dimensions);
+
+        let mut out = Vec::new();
+        let dimensions = art.render_aspect(2, 3, &mut out).unwrap();
+        assert_eq!(dimensions, (32, 4));
+        assert_eq!(measure(out), dimensions);
+    }
+
+    #[test]
+    fn suggested_palettes_expose_their_entries() {
+        // 8-bit entries of 6 bytes, then 16-bit entries of 10 bytes
+        let mut shallow = b"shades\0\x08".to_vec();
+        shallow.extend([255, 0, 0, 255, 0, 7, 0, 0, 255, 128, 0, 3]);
+        let mut deep = b"deep\0\x10".to_vec();
+        deep.extend([0x12, 0x34, 0, 0, 0, 0, 0xFF, 0xFF, 0, 9]);
+        let bytes = png(&[
+            ihdr(1, 1, 8, 2, false),
+            chunk(b"sPLT", &shallow),
+            chunk(b"sPLT", &deep),
+            idat(&[0, 1, 2, 3]),
+            chunk(b"IEND", &[]),
+        ]);
+        let image = decode(&bytes).unwrap();
+        let palettes = image.suggested_palettes();
+
+        assert_eq!(palettes.len(), 2);
+        assert_eq!(
+            (palettes[0].name.as_str(), palettes[0].sample_depth),
+            ("shades", 8)
+        );
+        assert_eq!(palettes[0].entries.len(), 2);
+        let blue = SuggestedPaletteEntry {
+            red: 0,
+            green: 0,
+            blue: 255,
+            alpha: 128,
+            frequency: 3,
+        };
+        assert_eq!(palettes[0].entries[1], blue);
+        assert_eq!(palettes[1].entries.len(), 1);
+        assert_eq!(palettes[1].entries[0].red, 0x1234);
+        assert_eq!(palettes[1].entries[0].alpha, 0xFFFF);
+
+        // entries that do not divide evenly
+        let mut ragged = shallow.clone();
+        ragged.push(0);
+        let bytes = png(&[
+            ihdr(1, 1, 8, 2, false),
+            chunk(b"sPLT", &ragged),
+            idat(&[0, 1, 2, 3]),
+            chunk(b"IEND", &[]),
+        ]);
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn bench_decode_times_every_phase() {
+        let stride = 256 * 3;
+        let rgb = unfiltered(&noise(stride * 256, 11), stride);
+        let result = bench_decode(&simple_png(256, 256, 8, 2, false, &rgb)).unwrap();
+
+        // the data is large enough for every phase to take measurable time
+        assert!(result.parse > Duration::ZERO, "{:?}", result);
+        assert!(result.decompress > Duration::ZERO, "{:?}", result);
+        assert!(result.unfilter > Duration::ZERO, "{:?}", result);
+        assert!(bench_decode(b"not a png").is_err());
+    }
+
+    #[test]
+    fn precise_sixteen_bit_greys_step_through_the_ramp_finer() {
+        // a dark gradient whose low bytes carry most of the detail
+        let deep = gradient(128, 1, 1, 2, |x, _, _| (x * 16) as u16);
+        let path = temp_file(
+            "precise_16bit.png",
+            &simple_png(128, 1, 16, 0, false, &deep),
+        );
+        let opts = DecodeOptions {
+            precise_16bit: true,
+            ..Default::default()
+        };
+        let precise = Img::with_options(&path, &opts).unwrap();
+        let truncated = Img::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let samples: Vec<u16> = (0..128).map(|x| x * 16).collect();
+        assert_eq!(precise.grid()[0], samples);
+        let render = RenderOptions {
+            size: Size::Exact(128, 1),
+            ..Default::default()
+        };
+        let row = &precise.ascii_rows(&render)[0];
+        let expected: String = samples.iter().map(|sample| glyph(*sample)).collect();
+        assert_eq!(*row, expected);
+
+        let distinct = |row: &str| row.chars().collect::<HashSet<_>>().len();
+        let coarse = &truncated.ascii_rows(&render)[0];
+        assert!(distinct(row) > distinct(coarse), "{:?} {:?}", row, coarse);
+    }
+
+    #[test]
+    fn labels_overwrite_the_art_and_stay_inside_it() {
+        let art = img(&simple_png(
+            4,
+            4,
+            8,
+            0,
+            false,
+            &gradient(4, 4, 1, 1, |_, _, _| 0),
+        ));
+        let opts = RenderOptions {
+            size: Size::Exact(10, 5),
+            ..Default::default()
+        };
+        let blank = art.ascii_rows(&opts);
+        let labeled = |row, col| {
+            let mut out = Vec::new();
+            art.render_with_label(&mut out, &opts, "HI", row, col)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let out = labeled(2, 3);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[2], format!("{}HI{}", &blank[2][..3], &blank[2][5..]));
+        assert_eq!(lines[1], blank[1]);
+
+        // out of bounds placements move back to the last row and column
+        let out = labeled(99, 99);
+        assert!(out.lines().nth(4).unwrap().ends_with("HI"));
+        assert_eq!(out.lines().count(), 5);
+
+        // and labels wider than the art are cut to its width
+        let mut out = Vec::new();
+        art.render_with_label(&mut out, &opts, "ABCDEFGHIJKL", 0, 4)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap().lines().next(),
+            Some("ABCDEFGHIJ")
+        );
+    }
+
+    #[test]
+    fn unknown_chunks_are_classified_by_their_property_bits() {
+        let with_chunk = |chunk_type: &[u8; 4]| {
+            png(&[
+                ihdr(1, 1, 8, 0, false),
+                chunk(chunk_type, b"payload"),
+                idat(&[0, 9]),
+                chunk(b"IEND", &[]),
+            ])
+        };
+
+        // ancillary chunks are skipped whatever their other bits are
+        for chunk_type in [b"prIv", b"prIV", b"puBl", b"quiR"] {
+            assert_eq!(decode(&with_chunk(chunk_type)).unwrap().data, [9]);
+        }
+        let err = decode(&with_chunk(b"PrIv")).unwrap_err();
+        assert_eq!(err.to_string(), "PrIv is an unknown critical PNG chunk");
+        let err = decode(&with_chunk(b"pr1v")).unwrap_err();
+        assert_eq!(err.to_string(), "pr1v is an invalid PNG chunk type");
+    }
+
+    #[test]
+    fn transparent_palette_entries_expand_to_their_alpha() {
+        // the tRNS chunk stops short of the last two entries
+        let bytes = png(&[
+            ihdr(4, 1, 8, 3, false),
+            chunk(
+                b"PLTE",
+                &[10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120],
+            ),
+            chunk(b"tRNS", &[0, 128]),
+            idat(&[0, 0, 1, 2, 3]),
+            chunk(b"IEND", &[]),
+        ]);
+        let pixels = decode(&bytes).unwrap().to_rgba8();
+        assert_eq!(
+            pixels,
+            [
+                [10, 20, 30, 0],
+                [40, 50, 60, 128],
+                [70, 80, 90, 255],
+                [100, 110, 120, 255]
+            ]
+        );
+    }
+
+    #[test]
+    fn doubled_characters_double_the_width() {
+        let grey = gradient(10, 4, 1, 1, |x, y, _| (x * 25 + y * 5) as u16);
+        let art = img(&simple_png(10, 4, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(10, 4),
+            ..Default::default()
+        };
+        let doubled = RenderOptions {
+            char_aspect_double: true,
+            ..opts.clone()
+        };
+
+        let mut out = Vec::new();
+        assert_eq!(art.render(&mut out, &doubled).unwrap(), (20, 4));
+        let out = String::from_utf8(out).unwrap();
+        for (row, plain) in out.lines().zip(art.ascii_rows(&opts)) {
+            let twice: String = plain.chars().flat_map(|ch| [ch, ch]).collect();
+            assert_eq!(row, twice);
+        }
+    }
+
+    #[test]
+    fn image_data_past_the_last_scanline_is_detected() {
+        // three bytes of padding after the two scanlines
+        let bytes = simple_png(2, 2, 8, 0, false, &[0, 1, 2, 0, 3, 4, 0, 0, 0]);
+        let err = decode(&bytes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "image data has 3 bytes past the last scanline"
+        );
+
+        let (image, diagnostics) = decode_lenient(&bytes);
+        assert_eq!(image.data, [1, 2, 3, 4]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("IDAT"));
+
+        // interlaced images too
+        let interlaced = interlace(&[1, 2, 3, 4], 2, 2, image.pixel_format());
+        let mut padded = interlaced.clone();
+        padded.push(0);
+        assert!(decode(&simple_png(2, 2, 8, 0, true, &interlaced)).is_ok());
+        assert!(decode(&simple_png(2, 2, 8, 0, true, &padded)).is_err());
+    }
+
+    #[test]
+    fn transparent_pixels_take_the_fill_character() {
+        let opts = RenderOptions {
+            size: Size::Exact(4, 1),
+            transparent_char: Some('.'),
+            ..Default::default()
+        };
+        let plain = RenderOptions {
+            transparent_char: None,
+            ..opts.clone()
+        };
+
+        // white, transparent white, black, and transparent black
+        let ga = [0, 255, 255, 255, 0, 0, 255, 0, 0];
+        let art = img(&simple_png(4, 1, 8, 4, false, &ga));
+        let row = &art.ascii_rows(&opts)[0];
+        let ramp = &art.ascii_rows(&plain)[0];
+        assert_eq!(
+            row.chars().map(|ch| ch == '.').collect::<Vec<_>>(),
+            [false, true, false, true]
+        );
+        assert_eq!(row.chars().next(), ramp.chars().next());
+        assert_ne!(ramp.chars().nth(1), Some('.'));
+
+        // the color key marks transparent pixels just the same
+        let keyed = png(&[
+            ihdr(4, 1, 8, 0, false),
+            chunk(b"tRNS", &[0, 0]),
+            idat(&[0, 255, 0, 128, 0]),
+            chunk(b"IEND", &[]),
+        ]);
+        assert_eq!(img(&keyed).ascii_rows(&opts)[0].matches('.').count(), 2);
+    }
+
+    #[test]
+    fn pixels_are_read_by_coordinate() {
+        let rgb = gradient(3, 2, 3, 1, |x, y, c| (x * 60 + y * 100 + c) as u16);
+        let image = decode(&simple_png(3, 2, 8, 2, false, &rgb)).unwrap();
+        assert_eq!(image.pixel(0, 0), Some([0, 1, 2, 255]));
+        assert_eq!(image.pixel(2, 1), Some([220, 221, 222, 255]));
+        assert_eq!(image.pixel(3, 0), None);
+        assert_eq!(image.pixel(0, 2), None);
+
+        // palette indices and their tRNS alpha are expanded
+        let bytes = png(&[
+            ihdr(2, 1, 1, 3, false),
+            chunk(b"PLTE", &[1, 2, 3, 4, 5, 6]),
+            chunk(b"tRNS", &[255, 7]),
+            idat(&[0, 0b0100_0000]),
+            chunk(b"IEND", &[]),
+        ]);
+        assert_eq!(decode(&bytes).unwrap().pixel(1, 0), Some([4, 5, 6, 7]));
+    }
+
+    /// Decodes padded standard base64
+    fn unbase64(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let sextets: Vec<u32> = text
+            .bytes()
+            .filter(|byte| *byte != b'=')
+            .map(|byte| ALPHABET.iter().position(|a| *a == byte).unwrap() as u32)
+            .collect();
+        let mut data = Vec::new();
+        for group in sextets.chunks(4) {
+            let bits = group
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (idx, sextet)| bits | sextet << (18 - 6 * idx));
+            data.extend(&bits.to_be_bytes()[1..group.len()]);
+        }
+        data
+    }
+
+    #[test]
+    fn kitty_payloads_decode_back_to_the_pixels() {
+        // enough pixels for the payload to span two escapes
+        let stride = 40 * 4;
+        let rgba = unfiltered(&noise(stride * 30, 5), stride);
+        let image = decode(&simple_png(40, 30, 8, 6, false, &rgba)).unwrap();
+        let mut out = Vec::new();
+        image.render_kitty(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(
+            out.starts_with("\x1b_Ga=T,f=32,s=40,v=30,m=1;"),
+            "{:?}",
+            &out[..40]
+        );
+        let escapes: Vec<_> = out.trim_end().split_terminator("\x1b\\").collect();
+        assert_eq!(escapes.len(), 2);
+        assert!(escapes[1].starts_with("\x1b_Gm=0;"));
+        let payload: String = escapes
+            .iter()
+            .map(|escape| &escape[escape.find(';').unwrap() + 1..])
+            .collect();
+        let pixels: Vec<u8> = image.to_rgba8().into_iter().flatten().collect();
+        assert_eq!(unbase64(&payload), pixels);
+    }
+
+    #[test]
+    fn stream_render_pipes_bytes_to_art() {
+        let grey = gradient(24, 12, 1, 1, |x, y, _| (x * 10 + y) as u16);
+        let bytes = simple_png(24, 12, 8, 0, false, &grey);
+        let opts = RenderOptions {
+            size: Size::Height(6),
+            ..Default::default()
+        };
+
+        let mut out = Vec::new();
+        let dimensions = stream_render(io::Cursor::new(&bytes), &mut out, &opts).unwrap();
+        let art = String::from_utf8(out).unwrap();
+        assert_eq!(dimensions, (24, 6));
+        assert_eq!(art.lines().count(), 6);
+        assert!(art.chars().any(|ch| ch != ' ' && ch != '\n'));
+        assert_eq!(art, img(&bytes).to_ascii_string(&opts));
+
+        assert!(stream_render(&b"not a png"[..], io::sink(), &opts).is_err());
+    }
+
+    #[test]
+    fn budgets_bound_the_character_count() {
+        let grey = gradient(64, 64, 1, 1, |x, y, _| (x + y) as u16);
+        let square = img(&simple_png(64, 64, 8, 0, false, &grey));
+        assert_eq!(square.budget_size(100), Size::Exact(10, 10));
+
+        let grey = gradient(64, 32, 1, 1, |x, y, _| (x + y) as u16);
+        let wide = img(&simple_png(64, 32, 8, 0, false, &grey));
+        assert_eq!(wide.budget_size(100), Size::Exact(14, 7));
+        assert_eq!(wide.budget_size(1), Size::Exact(0, 0));
+
+        let opts = RenderOptions {
+            size: wide.budget_size(500),
+            ..Default::default()
+        };
+        // a row more would take 512 characters
+        assert_eq!(wide.render(&mut io::sink(), &opts).unwrap(), (30, 15));
+    }
+
+    #[test]
+    fn digests_are_pinned_for_a_fixed_image() {
+        let grey = gradient(16, 8, 1, 1, |x, y, _| (x * 16 + y * 2) as u16);
+        let bytes = simple_png(16, 8, 8, 0, false, &grey);
+        let opts = RenderOptions {
+            size: Size::Exact(16, 8),
+            ..Default::default()
+        };
+        let art = img(&bytes);
+        // a change to this value changes the art of every golden test
+        assert_eq!(art.ascii_digest(&opts), 5427556888087983093);
+        assert_eq!(img(&bytes).ascii_digest(&opts), art.ascii_digest(&opts));
+
+        let mirrored = RenderOptions {
+            mirror: true,
+            ..opts.clone()
+        };
+        assert_ne!(art.ascii_digest(&mirrored), art.ascii_digest(&opts));
+        // the legend is not part of the art
+        let legend = RenderOptions {
+            with_legend: true,
+            ..opts
+        };
+        assert_eq!(art.ascii_digest(&legend), 5427556888087983093);
+    }
+
+    #[test]
+    fn borders_frame_every_row_of_the_art() {
+        let grey = gradient(12, 6, 1, 1, |x, y, _| (x * 20 + y) as u16);
+        let art = img(&simple_png(12, 6, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(12, 6),
+            border: true,
+            ..Default::default()
+        };
+        let rows = art.ascii_rows(&opts);
+        let out = art.to_ascii_string(&opts);
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], format!("┌{}┐", "─".repeat(12)));
+        assert_eq!(lines[7], format!("└{}┘", "─".repeat(12)));
+        for (line, row) in lines[1..7].iter().zip(&rows) {
+            assert_eq!(*line, format!("│{}│", row));
+        }
+    }
+
+    #[test]
+    fn scaled_copies_hash_alike() {
+        // a bright disc off center over a gradient, at any size
+        let scene = |size: usize| {
+            let grey = gradient(size, size, 1, 1, |x, y, _| {
+                let (u, v) = (x as f32 / size as f32, y as f32 / size as f32);
+                let disc = ((u - 0.3).powi(2) + (v - 0.6).powi(2)).sqrt() < 0.25;
+                if disc {
+                    255
+                } else {
+                    (u * 120.0) as u16
+                }
+            });
+            decode(&simple_png(size as u32, size as u32, 8, 0, false, &grey)).unwrap()
+        };
+        let distance = |a: u64, b: u64| (a ^ b).count_ones();
+
+        let original = scene(64).phash();
+        assert_ne!(original, 0);
+        assert!(distance(original, scene(57).phash()) <= 4);
+        assert!(distance(original, scene(100).phash()) <= 4);
+
+        // the negative has nearly every bit flipped
+        let mut negative = scene(64);
+        negative
+            .data
+            .iter_mut()
+            .for_each(|grey| *grey = 255 - *grey);
+        assert!(distance(original, negative.phash()) >= 48);
+    }
+
+    #[test]
+    fn mirrored_rows_are_reversed() {
+        let grey = gradient(20, 6, 1, 1, |x, y, _| (x * 12 + y * 3) as u16);
+        let art = img(&simple_png(20, 6, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(20, 6),
+            ..Default::default()
+        };
+        let mirrored = RenderOptions {
+            mirror: true,
+            ..opts.clone()
+        };
+
+        let rows = art.ascii_rows(&opts);
+        let reversed: Vec<String> = rows.iter().map(|row| row.chars().rev().collect()).collect();
+        assert_ne!(rows, reversed);
+        assert_eq!(art.ascii_rows(&mirrored), reversed);
+    }
+
+    #[test]
+    fn brightness_windows_blank_what_falls_outside() {
+        // dark on the left, bright steps from 200 to 255 on the right
+        let grey = gradient(8, 2, 1, 1, |x, _, _| {
+            [50, 50, 50, 50, 200, 220, 240, 255][x]
+        });
+        let art = img(&simple_png(8, 2, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(8, 2),
+            ..Default::default()
+        };
+        let windowed = RenderOptions {
+            brightness_window: Some((200, 255)),
+            ..opts.clone()
+        };
+
+        let plain = &art.ascii_rows(&opts)[0];
+        assert!(plain[..4].chars().all(|ch| ch != ' '), "{:?}", plain);
+        let row = &art.ascii_rows(&windowed)[0];
+        assert_eq!(&row[..4], "    ");
+        // the window stretches over the whole ramp
+        let stretched = [0u32, 20 * 65535 / 55, 40 * 65535 / 55, 65535];
+        let expected: String = stretched.iter().map(|grey| glyph(*grey as u16)).collect();
+        assert_eq!(&row[4..], expected);
+        assert!(row.ends_with('$'));
+    }
+
+    #[test]
+    fn missing_iend_chunks_are_tolerated_leniently() {
+        let complete = simple_png(2, 1, 8, 0, false, &[0, 30, 60]);
+        let without_iend = &complete[..complete.len() - 12];
+        let err = decode(without_iend).unwrap_err();
+        assert_eq!(err.to_string(), "file ends without an IEND chunk");
+
+        let (image, diagnostics) = decode_lenient(without_iend);
+        assert_eq!(image.data, [30, 60]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("IEND"));
+
+        // the image data itself must still be complete
+        let short = png(&[ihdr(2, 2, 8, 0, false), idat(&[0, 30, 60])]);
+        let opts = DecodeOptions {
+            lenient: true,
+            ..Default::default()
+        };
+        assert!(decode_with(&short, &opts).is_err());
+    }
+
+    #[test]
+    fn full_width_cells_halve_the_columns() {
+        let opts = RenderOptions {
+            size: Size::Height(10),
+            ..Default::default()
+        };
+        let wide = RenderOptions {
+            full_width_cells: true,
+            ..opts.clone()
+        };
+        assert_eq!(opts.dimensions(40, 20), (40, 10));
+        assert_eq!(wide.dimensions(40, 20), (20, 10));
+
+        // each column is written as one full-width character
+        let grey = gradient(40, 20, 1, 1, |x, y, _| (x * 6 + y) as u16);
+        let art = img(&simple_png(40, 20, 8, 0, false, &grey));
+        let rows = art.ascii_rows(&wide);
+        assert_eq!(rows.len(), 10);
+        for row in &rows {
+            assert_eq!(row.chars().count(), 20);
+            assert!(row
+                .chars()
+                .all(|ch| ch == '\u{3000}' || ('\u{ff01}'..='\u{ff5e}').contains(&ch)));
+        }
+        assert_eq!(full_width('a'), 'ａ');
+    }
+
+    #[test]
+    fn bitmaps_split_a_gradient_at_the_threshold() {
+        // 0 to 252 left to right
+        let grey = gradient(64, 4, 1, 1, |x, _, _| (x * 4) as u16);
+        let art = img(&simple_png(64, 4, 8, 0, false, &grey));
+
+        let bitmap = art.to_bitmap(128, 64, 4);
+        assert_eq!(bitmap.len(), 4);
+        let expected: Vec<bool> = (0..64).map(|x| x >= 32).collect();
+        assert!(bitmap.iter().all(|row| *row == expected));
+
+        // at a lower resolution the split stays halfway
+        let bitmap = art.to_bitmap(128, 8, 2);
+        assert_eq!(
+            bitmap[0],
+            [false, false, false, false, true, true, true, true]
+        );
+        assert_eq!(bitmap.len(), 2);
+    }
+
+    #[test]
+    fn bkgd_colors_the_background_behind_the_art() {
+        let bytes = png(&[
+            ihdr(2, 1, 8, 2, false),
+            chunk(b"bKGD", &[0, 10, 0, 20, 0, 30]),
+            idat(&[0, 255, 255, 255, 0, 0, 0]),
+            chunk(b"IEND", &[]),
+        ]);
+        let art = img(&bytes);
+        let opts = RenderOptions {
+            size: Size::Exact(2, 2),
+            background_from_metadata: true,
+            ..Default::default()
+        };
+
+        let out = art.to_ascii_string(&opts);
+        assert_eq!(out.lines().count(), 2);
+        for line in out.lines() {
+            assert!(line.starts_with("\x1b[48;2;10;20;30m"), "{:?}", line);
+            assert!(line.ends_with("\x1b[0m"));
+        }
+        let mut html = Vec::new();
+        art.render_html(&mut html, &opts).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(
+            html.contains("<body style=\"background-color: #0a141e\">"),
+            "{}",
+            html
+        );
+
+        // only when asked for, and never without ANSI
+        let plain = RenderOptions {
+            background_from_metadata: false,
+            ..opts.clone()
+        };
+        assert!(!art.to_ascii_string(&plain).contains('\x1b'));
+        let no_ansi = RenderOptions {
+            no_ansi: true,
+            ..opts
+        };
+        assert!(!art.to_ascii_string(&no_ansi).contains('\x1b'));
+    }
+
+    #[test]
+    fn retained_bytes_are_the_file_contents() {
+        let bytes = sample_png();
+        let path = temp_file("retained.png", &bytes);
+        let (image, retained) = Image::from_retaining_bytes(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(retained, bytes);
+        assert_eq!(image.data, decode(&bytes).unwrap().data);
+        assert!(Image::from_retaining_bytes(&path).is_err());
+    }
+
+    #[test]
+    fn trimming_drops_only_trailing_spaces() {
+        let grey = [0, 0, 255, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let art = img(&simple_png(6, 2, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(6, 2),
+            trim_trailing_spaces: true,
+            ..Default::default()
+        };
+        assert_eq!(art.to_ascii_string(&opts), " $ $\n\n");
+
+        let untrimmed = RenderOptions {
+            trim_trailing_spaces: false,
+            ..opts.clone()
+        };
+        assert_eq!(art.to_ascii_string(&untrimmed), " $ $  \n      \n");
+        let framed = RenderOptions {
+            border: true,
+            ..opts
+        };
+        assert!(art.to_ascii_string(&framed).contains("│ $ $  │"));
+    }
+
+    #[test]
+    fn palette_indices_are_unpacked_exactly() {
+        // 2-bit rows padded out to whole bytes
+        let bytes = png(&[
+            ihdr(5, 2, 2, 3, false),
+            chunk(b"PLTE", &[0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3]),
+            idat(&[0, 0b0001_1011, 0b0100_0000, 0, 0b1110_0100, 0b1000_0000]),
+            chunk(b"IEND", &[]),
+        ]);
+        let indices = decode(&bytes).unwrap().palette_indices();
+        assert_eq!(indices, Some(vec![0, 1, 2, 3, 1, 3, 2, 1, 0, 2]));
+
+        assert_eq!(decode(&sample_png()).unwrap().palette_indices(), None);
+    }
+
+    #[test]
+    fn big_endian_reads_are_bounds_checked() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        assert_eq!(read_u16_be(&data, 0).unwrap(), 0x1234);
+        assert_eq!(read_u16_be(&data, 3).unwrap(), 0x789A);
+        assert_eq!(read_u32_be(&data, 0).unwrap(), 0x1234_5678);
+        assert_eq!(read_u32_be(&data, 1).unwrap(), 0x3456_789A);
+
+        let err = read_u16_be(&data, 4).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chunk ends before a 2-byte field at offset 4"
+        );
+        let err = read_u32_be(&data, 2).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chunk ends before a 4-byte field at offset 2"
+        );
+        assert!(read_u32_be(&data, usize::MAX - 1).is_err());
+    }
+
+    #[test]
+    fn coverage_ramps_pick_the_nearest_coverage_glyph() {
+        // I covers 164 of 255 although it sits early in the ramp
+        assert_eq!(coverage_glyph(widen(164)), 'I');
+        assert_ne!(glyph(widen(164)), 'I');
+        assert_eq!(coverage_glyph(widen(255)), 'B');
+        assert_eq!(coverage_glyph(0), ' ');
+
+        let art = img(&simple_png(2, 1, 8, 0, false, &[0, 164, 255]));
+        let opts = RenderOptions {
+            size: Size::Exact(2, 1),
+            coverage_ramp: true,
+            ..Default::default()
+        };
+        assert_eq!(art.ascii_rows(&opts), ["IB"]);
+    }
+
+    #[test]
+    fn palettes_on_greyscale_images_are_dropped_leniently() {
+        let ramp: Vec<u8> = (0..=255u8).flat_map(|grey| [255 - grey; 3]).collect();
+        let bytes = png(&[
+            ihdr(2, 1, 8, 0, false),
+            chunk(b"PLTE", &ramp),
+            idat(&[0, 10, 200]),
+            chunk(b"IEND", &[]),
+        ]);
+        let err = decode(&bytes).unwrap_err();
+        assert!(
+            err.to_string().contains("cannot have a PLTE chunk"),
+            "{}",
+            err
+        );
+
+        // the samples are grey levels, not indices into the inverted ramp
+        let (image, diagnostics) = decode_lenient(&bytes);
+        assert_eq!(image.color_type, ColorType::Greyscale);
+        assert_eq!(image.to_rgba8(), [[10, 10, 10, 255], [200, 200, 200, 255]]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("PLTE"));
+    }
+
+    #[test]
+    fn alpha_masks_show_the_opaque_disc() {
+        // white everywhere, opaque in the middle fading out to the edges
+        let ga = gradient(16, 16, 2, 1, |x, y, c| {
+            let distance = ((x as f32 - 7.5).powi(2) + (y as f32 - 7.5).powi(2)).sqrt();
+            [255, (255.0 * (1.0 - distance / 6.0)).max(0.0) as u16][c]
+        });
+        let art = img(&simple_png(16, 16, 8, 4, false, &ga));
+        let opts = RenderOptions {
+            size: Size::Exact(16, 16),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(art.render_alpha(&mut out, &opts).unwrap(), (16, 16));
+        let out = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<char>> = out.lines().map(|line| line.chars().collect()).collect();
+
+        let density = |ch: char| RAMP.find(ch).unwrap();
+        assert!(density(rows[7][7]) > 50 && density(rows[8][8]) > 50);
+        for (r, c) in [(0, 0), (0, 15), (15, 0), (15, 15), (0, 7), (7, 0)] {
+            assert_eq!(rows[r][c], ' ');
+        }
+        // density falls off with the distance from the middle
+        assert!(
+            density(rows[7][7]) > density(rows[7][10])
+                && density(rows[7][10]) > density(rows[7][12])
+        );
+        let mirrored: Vec<Vec<char>> = rows.iter().rev().cloned().collect();
+        assert_eq!(rows, mirrored);
+    }
+
+    #[test]
+    fn discarding_decodes_still_check_the_data() {
+        let bytes = sample_png();
+        assert!(Image::decode_and_discard(&bytes).is_ok());
+        assert!(Image::decode_and_discard(&bytes).is_ok());
+
+        // an unknown filter type, then an overwritten IDAT chunk
+        let bad_filter = simple_png(1, 1, 8, 0, false, &[9, 0]);
+        assert!(Image::decode_and_discard(&bad_filter).is_err());
+        let mut corrupt = simple_png(4, 4, 8, 0, false, &[0; 20]);
+        let idat = corrupt
+            .windows(4)
+            .position(|window| window == b"IDAT")
+            .unwrap();
+        corrupt[idat + 4..idat + 8].copy_from_slice(b"junk");
+        assert!(Image::decode_and_discard(&corrupt).is_err());
+    }
+
+    #[test]
+    fn pixel_formats_exist_only_for_valid_pairs() {
+        let color_types = [
+            ColorType::Greyscale,
+            ColorType::RGB,
+            ColorType::PaletteIndex,
+            ColorType::GreyscaleAlpha,
+            ColorType::RGBA,
+        ];
+        let valid: &[&[u8]] = &[
+            &[1, 2, 4, 8, 16],
+            &[8, 16],
+            &[1, 2, 4, 8],
+            &[8, 16],
+            &[8, 16],
+        ];
+        let mut formats = Vec::new();
+        for (color_type, depths) in color_types.into_iter().zip(valid) {
+            for bit_depth in [0, 1, 2, 3, 4, 8, 12, 16, 32] {
+                match PixelFormat::new(color_type, bit_depth) {
+                    Ok(format) => {
+                        assert!(depths.contains(&bit_depth), "{} {}", color_type, bit_depth);
+                        assert_eq!(
+                            (format.color_type(), format.bit_depth()),
+                            (color_type, bit_depth)
+                        );
+                        formats.push(format);
+                    }
+                    Err(_) => assert!(!depths.contains(&bit_depth), "{} {}", color_type, bit_depth),
+                }
+            }
+        }
+        assert_eq!(formats.len(), 15);
+
+        // packed pixels share bytes, 16-bit ones take two per channel
+        let strides: Vec<_> = [
+            PixelFormat::Gray1,
+            PixelFormat::Indexed4,
+            PixelFormat::Rgba16,
+        ]
+        .iter()
+        .map(|format| {
+            (
+                format.bits_per_pixel(),
+                format.stride(3),
+                format.filter_bpp(),
+            )
+        })
+        .collect();
+        assert_eq!(strides, [(1, 1, 1), (4, 2, 1), (64, 24, 8)]);
+    }
+
+    #[test]
+    fn identical_glyphs_collapse_into_one_run() {
+        let grey = [0, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 255, 255];
+        let art = img(&simple_png(6, 2, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(6, 2),
+            ..Default::default()
+        };
+        assert_eq!(
+            art.to_runs(&opts),
+            [vec![('$', 6)], vec![(' ', 4), ('$', 2)]]
+        );
+
+        // the runs expand back to the art
+        let expanded: Vec<String> = art
+            .to_runs(&opts)
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .flat_map(|(ch, count)| std::iter::repeat_n(*ch, *count))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(expanded, art.ascii_rows(&opts));
+    }
+
+    #[test]
+    fn heatmaps_run_from_purple_to_yellow() {
+        let art = img(&simple_png(3, 1, 8, 0, false, &[0, 0, 128, 255]));
+        let opts = RenderOptions {
+            size: Size::Exact(3, 1),
+            ..Default::default()
+        };
+        let mut map = Vec::new();
+        art.render_heatmap(&mut map, &opts).unwrap();
+        let map = String::from_utf8(map).unwrap();
+
+        // the endpoints of the viridis scale, with teal in the middle
+        let expected = [[68, 1, 84], viridis(widen(128)), [253, 231, 37]]
+            .map(|[r, g, b]| format!("\x1b[38;2;{};{};{}m█", r, g, b))
+            .concat();
+        assert_eq!(map, format!("{}\x1b[0m\n", expected));
+        let [r, g, b] = viridis(widen(128));
+        assert!(g > r && b > r, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn preset_dictionaries_resolve_back_references() {
+        // a fixed Huffman deflate block copying all four filtered bytes from
+        // the dictionary: length 4 (code 258), distance 4 (code 3), end of block
+        let mut bits = vec![1, 1, 0];
+        let mut code =
+            |value: u32, len: u32| bits.extend((0..len).rev().map(|bit| value >> bit & 1));
+        code(0b000_0010, 7);
+        code(3, 5);
+        code(0, 7);
+        let deflate: Vec<u8> = bits
+            .chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .fold(0, |acc, (idx, bit)| acc | (*bit as u8) << idx)
+            })
+            .collect();
+
+        let filtered = [0, 10, 20, 30];
+        let mut compressed = vec![0x78, 0x20];
+        compressed.extend(adler32(&filtered).to_be_bytes());
+        compressed.extend(deflate);
+        compressed.extend(adler32(&filtered).to_be_bytes());
+        let bytes = png(&[
+            ihdr(3, 1, 8, 0, false),
+            chunk(b"IDAT", &compressed),
+            chunk(b"IEND", &[]),
+        ]);
+
+        let err = decode(&bytes).unwrap_err();
+        assert_eq!(err.to_string(), "image data needs a preset zlib dictionary");
+        let with = |dictionary: &[u8]| DecodeOptions {
+            zlib_dictionary: Some(dictionary.to_vec()),
+            ..Default::default()
+        };
+        let err = decode_with(&bytes, &with(b"other")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "image data needs a different preset zlib dictionary"
+        );
+        assert_eq!(
+            decode_with(&bytes, &with(&filtered)).unwrap().data,
+            [10, 20, 30]
+        );
+    }
+
+    #[test]
+    fn truncated_chunks_report_their_type_and_offset() {
+        // the tEXt chunk starts after the signature and IHDR, 24 bytes long
+        let bytes = sample_png();
+        assert_eq!(&bytes[37..41], b"tEXt");
+
+        let err = decode(&bytes[..43]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tEXt chunk at byte 33 needs 24 bytes, but the file ends 10 bytes into it"
+        );
+        let err = decode(&bytes[..39]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file ends in the middle of a chunk header at byte 33"
+        );
+        let err = Image::from_unseekable_reader(&bytes[..43]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file ends in the middle of the tEXt chunk, which needs 24 bytes"
+        );
+    }
+
+    #[test]
+    fn mipmaps_halve_each_level() {
+        let grey = gradient(32, 16, 1, 1, |x, y, _| (x * 8 + y) as u16);
+        let art = img(&simple_png(32, 16, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(16, 8),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(art.render_mipmap(&mut out, &opts, 3).unwrap(), 3);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+
+        // each level is its label followed by its rows
+        let mut at = 0;
+        for (cols, rows) in [(16, 8), (8, 4), (4, 2)] {
+            assert_eq!(lines[at], format!("{}x{}", cols, rows));
+            let block = &lines[at + 1..at + 1 + rows];
+            assert!(block.iter().all(|line| line.chars().count() == cols));
+            at += 1 + rows;
+        }
+        assert_eq!(lines.len(), at);
+
+        // levels stop at a single character
+        let mut out = Vec::new();
+        assert_eq!(art.render_mipmap(&mut out, &opts, 10).unwrap(), 5);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[lines.len() - 2], "1x1");
+        assert_eq!(lines[lines.len() - 1].chars().count(), 1);
+    }
+
+    #[test]
+    fn content_bounds_hug_the_opaque_square() {
+        // an opaque 3x2 block at (2, 3) of an otherwise transparent 8x8 image
+        let ga = gradient(8, 8, 2, 1, |x, y, c| {
+            let inside = (2..5).contains(&x) && (3..5).contains(&y);
+            [200, if inside { 255 } else { 0 }][c]
+        });
+        let image = decode(&simple_png(8, 8, 8, 4, false, &ga)).unwrap();
+        assert_eq!(image.content_bounds(), Some((2, 3, 3, 2)));
+
+        let clear = gradient(4, 4, 2, 1, |_, _, _| 0);
+        assert_eq!(
+            decode(&simple_png(4, 4, 8, 4, false, &clear))
+                .unwrap()
+                .content_bounds(),
+            None
+        );
+
+        // color keys count as transparency, opaque images are all content
+        let keyed = png(&[
+            ihdr(4, 1, 8, 0, false),
+            chunk(b"tRNS", &[0, 7]),
+            idat(&[0, 7, 1, 7, 7]),
+            chunk(b"IEND", &[]),
+        ]);
+        assert_eq!(decode(&keyed).unwrap().content_bounds(), Some((1, 0, 1, 1)));
+        assert_eq!(
+            decode(&sample_png()).unwrap().content_bounds(),
+            Some((0, 0, 5, 3))
+        );
+    }
+
+    #[test]
+    fn half_transparent_white_greys_by_target() {
+        let bytes = simple_png(1, 1, 8, 4, false, &[0, 255, 128]);
+        let grey = |target| {
+            let image = decode_with(&bytes, &DecodeOptions::for_target(target)).unwrap();
+            assert_eq!(image.color_type, ColorType::Greyscale);
+            image.data[0]
+        };
+
+        // half white over the dark terminal, all but white on the light page
+        assert_eq!(grey(RenderTarget::Terminal), 128);
+        assert_eq!(grey(RenderTarget::Html), 255);
+        assert_eq!(RenderTarget::Terminal.background(), [0, 0, 0]);
+
+        let half_grey = simple_png(1, 1, 8, 4, false, &[0, 100, 128]);
+        let over = |target| {
+            decode_with(&half_grey, &DecodeOptions::for_target(target))
+                .unwrap()
+                .data
+        };
+        assert_eq!(
+            (over(RenderTarget::Terminal), over(RenderTarget::Html)),
+            (vec![50], vec![177])
+        );
+    }
+
+    #[test]
+    fn normal_map_channels_render_one_by_one() {
+        // x in red, y flat out in green, no blue, all opaque
+        let rgba = gradient(4, 1, 4, 1, |x, _, c| [x as u16 * 85, 255, 0, 255][c]);
+        let art = img(&simple_png(4, 1, 8, 6, false, &rgba));
+        let opts = RenderOptions {
+            size: Size::Exact(4, 1),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(art.render_channels(&mut out, &opts).unwrap(), 4);
+        let out = String::from_utf8(out).unwrap();
+
+        let red: String = [0, 85, 170, 255]
+            .iter()
+            .map(|red| glyph(widen(*red)))
+            .collect();
+        let expected = format!("red\n{}\ngreen\n$$$$\nblue\n    \nalpha\n$$$$\n", red);
+        assert_eq!(out, expected);
+
+        // the luma formula would have mixed them into one plane instead
+        assert_ne!(art.ascii_rows(&opts)[0], red);
+    }
+
+    #[test]
+    fn chunks_after_iend_are_violations() {
+        let image = simple_png(2, 1, 8, 0, false, &[0, 5, 6]);
+        let mut trailing = image.clone();
+        trailing.extend(chunk(b"tEXt", b"Title\0late"));
+        let offset = image.len();
+
+        let err = decode(&trailing).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("tEXt chunk at byte {} comes after the IEND chunk", offset)
+        );
+        let (decoded, diagnostics) = decode_lenient(&trailing);
+        assert_eq!(decoded.data, [5, 6]);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk.as_deref(), Some("tEXt"));
+
+        // bytes that do not look like a chunk are tolerated
+        let mut garbage = image.clone();
+        garbage.extend(b"appended by some tool");
+        assert_eq!(decode(&garbage).unwrap().data, [5, 6]);
+    }
+
+    #[test]
+    fn ramp_luts_span_the_ramp() {
+        let ramp: Vec<char> = RAMP.chars().collect();
+        let lut = ramp_lut(&ramp);
+        assert_eq!(lut[0], ' ');
+        assert_eq!(lut[255], '$');
+
+        // the same steps as the rendered art
+        let art = img(&simple_png(
+            256,
+            1,
+            8,
+            0,
+            false,
+            &gradient(256, 1, 1, 1, |x, _, _| x as u16),
+        ));
+        let opts = RenderOptions {
+            size: Size::Exact(256, 1),
+            ..Default::default()
+        };
+        assert_eq!(art.ascii_rows(&opts)[0], lut.iter().collect::<String>());
+
+        // a two character ramp splits at the halfway point
+        let mut halves = ['.'; 256];
+        halves[128..].fill('#');
+        assert_eq!(ramp_lut(&['.', '#']), halves);
+        assert!(ramp_lut(&[]).iter().all(|glyph| *glyph == ' '));
+    }
+
+    #[test]
+    fn premultiplied_colors_are_divided_by_their_alpha() {
+        // half transparent orange stored premultiplied, then a clear pixel
+        let rgba = [0, 128, 64, 0, 128, 0, 0, 0, 0];
+        let bytes = simple_png(2, 1, 8, 6, false, &rgba);
+        let opts = DecodeOptions {
+            assume_premultiplied: true,
+            ..Default::default()
+        };
+        let image = decode_with(&bytes, &opts).unwrap();
+        assert_eq!(image.to_rgba8(), [[255, 128, 0, 128], [0, 0, 0, 0]]);
+        assert_eq!(decode(&bytes).unwrap().pixel(0, 0), Some([128, 64, 0, 128]));
+
+        // and before 16-bit samples are reduced to 8 bits
+        let rgba16 = gradient(1, 1, 4, 2, |_, _, c| [0x4000, 0x2000, 0, 0x8000][c]);
+        let image = decode_with(&simple_png(1, 1, 16, 6, false, &rgba16), &opts).unwrap();
+        assert_eq!(image.pixel(0, 0), Some([128, 64, 0, 128]));
+    }
+
+    #[test]
+    fn posterized_art_uses_at_most_the_given_levels() {
+        let grey = gradient(64, 8, 1, 1, |x, y, _| (x * 4 + y) as u16);
+        let art = img(&simple_png(64, 8, 8, 0, false, &grey));
+        let opts = RenderOptions {
+            size: Size::Exact(64, 8),
+            levels: 4,
+            ..Default::default()
+        };
+        let glyphs = |opts: &RenderOptions| {
+            art.ascii_rows(opts)
+                .concat()
+                .chars()
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(glyphs(&opts).len(), 4);
+        assert!(
+            glyphs(&RenderOptions {
+                levels: 0,
+                ..opts.clone()
+            })
+            .len()
+                > 50
+        );
+
+        // the levels run from the darkest glyph to the densest
+        let posterized = glyphs(&opts);
+        assert!(posterized.contains(&' ') && posterized.contains(&'$'));
+        assert_eq!(glyphs(&RenderOptions { levels: 1, ..opts }).len(), 1);
+    }
+
+    #[test]
+    fn streamed_rows_of_a_large_image_match_the_buffered_render() {
+        // every filter type in turn, over scanlines that do not divide evenly
+        // into the output rows
+        let (width, height) = (40, 31);
+        let mut filtered = Vec::new();
+        for (row, bytes) in noise(width * height, 17).chunks(width).enumerate() {
+            filtered.push(row as u8 % 5);
+            filtered.extend(bytes);
+        }
+        let bytes = simple_png(40, 31, 8, 0, false, &filtered);
+
+        let mut rows = Vec::new();
+        let dimensions =
+            Image::stream_ascii_rows(&bytes[..], 3, |row| rows.push(row.to_owned())).unwrap();
+        assert_eq!(dimensions, (14, 11));
+        let opts = RenderOptions {
+            size: Size::Exact(14, 11),
+            ..Default::default()
+        };
+        assert_eq!(rows, img(&bytes).ascii_rows(&opts));
+    }
+
+    #[test]
+    fn packed_palette_rows_filter_whole_bytes() {
+        // 7 two-bit indices take two bytes, the last one partly padding
+        let indices = [[3, 0, 1, 2, 2, 1, 0], [1, 1, 3, 3, 0, 2, 1]];
+        let palette = chunk(
+            b"PLTE",
+            &[0, 0, 0, 90, 90, 90, 180, 180, 180, 255, 255, 255],
+        );
+        let mut filtered = Vec::new();
+        let mut above = [0u8; 2];
+        for (row, filter) in indices.iter().zip([1u8, 2]) {
+            let packed = [
+                row[0] << 6 | row[1] << 4 | row[2] << 2 | row[3],
+                row[4] << 6 | row[5] << 4 | row[6] << 2,
+            ];
+            // Sub and Up work on the packed bytes, the one to the left a byte back
+            let prior = match filter {
+                1 => [0, packed[0]],
+                _ => above,
+            };
+            filtered.push(filter);
+            filtered.extend([
+                packed[0].wrapping_sub(prior[0]),
+                packed[1].wrapping_sub(prior[1]),
+            ]);
+            above = packed;
+        }
+        let bytes = png(&[
+            ihdr(7, 2, 2, 3, false),
+            palette,
+            idat(&filtered),
+            chunk(b"IEND", &[]),
+        ]);
+
+        let image = decode(&bytes).unwrap();
+        assert_eq!(image.palette_indices().unwrap(), indices.concat());
+        assert_eq!(image.pixel(6, 1), Some([90, 90, 90, 255]));
+        assert_eq!(image.pixel(0, 0), Some([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn palette_previews_stop_before_the_image_data() {
+        // the image data is not even zlib, so decoding it would fail
+        let palette = [255, 0, 0, 0, 128, 0, 0, 0, 255];
+        let bytes = png(&[
+            ihdr(2, 2, 8, 3, false),
+            chunk(b"PLTE", &palette),
+            chunk(b"IDAT", b"not zlib"),
+            chunk(b"IEND", &[]),
+        ]);
+        assert!(decode(&bytes).is_err());
+        let path = temp_file("palette_preview.png", &bytes);
+        let preview = Image::palette_preview(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(preview, Some(vec![[255, 0, 0], [0, 128, 0], [0, 0, 255]]));
+
+        let path = temp_file("palette_preview_rgb.png", &sample_png());
+        let preview = Image::palette_preview(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(preview, None);
+    }
+
+    #[test]
+    fn repeated_glyphs_double_the_width_and_keep_the_rows() {
+        let art = img(&sample_png());
+        let mut out = Vec::new();
+        assert_eq!(art.render_aspect(2, 1, &mut out).unwrap(), (10, 3));
+        let doubled = String::from_utf8(out).unwrap();
+
+        let opts = RenderOptions {
+            size: Size::Exact(5, 3),
+            ..Default::default()
+        };
+        let rows = art.ascii_rows(&opts);
+        assert_eq!(rows.len(), art.grid().len());
+        let lines: Vec<_> = doubled.lines().collect();
+        assert_eq!(lines.len(), rows.len());
+        for (line, row) in lines.iter().zip(&rows) {
+            let expected: String = row.chars().flat_map(|ch| [ch, ch]).collect();
+            assert_eq!(*line, expected);
+        }
+    }
+
+    #[test]
+    fn images_diffed_against_themselves_are_black() {
+        let image = decode(&sample_png()).unwrap();
+        let diff = image.diff(&image).unwrap();
+        assert_eq!((diff.width, diff.height), (5, 3));
+        assert!(diff.data.iter().all(|byte| *byte == 0));
+
+        let other = decode(&simple_png(5, 2, 8, 0, false, &[0; 12])).unwrap();
+        let err = image.diff(&other).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "can not diff a 5x3 image with a 5x2 image");
+    }
+
+    #[test]
+    fn adaptive_tiles_bring_out_detail_in_dark_and_bright_halves() {
+        // faint stripes on a dark left half and a bright right half
+        let filtered = gradient(8, 4, 1, 1, |x, _, _| {
+            let stripe = (x % 2) as u16 * 12;
+            if x < 4 {
+                stripe
+            } else {
+                243 + stripe
+            }
+        });
+        let art = img(&simple_png(8, 4, 8, 0, false, &filtered));
+        let glyphs = |opts: &RenderOptions| {
+            let rows = art.ascii_rows(opts);
+            let half = |skip: usize| {
+                let mut chars: Vec<char> = rows
+                    .iter()
+                    .flat_map(|row| row.chars().skip(skip).take(4))
+                    .collect();
+                chars.sort_unstable();
+                chars.dedup();
+                chars.len()
+            };
+            (half(0), half(4))
+        };
+
+        let opts = RenderOptions {
+            size: Size::Exact(8, 4),
+            ..Default::default()
+        };
+        let (dark, bright) = glyphs(&opts);
+        assert!(dark < 3 && bright < 3, "{:?}", art.ascii_rows(&opts));
+        let adaptive = RenderOptions {
+            adaptive_tile: Some(4),
+            ..opts
+        };
+        let rows = art.ascii_rows(&adaptive);
+        assert_eq!(glyphs(&adaptive), (2, 2), "{:?}", rows);
+        // the stripes span the whole ramp in both halves
+        for row in &rows {
+            assert_eq!(row[..4], row[4..], "{:?}", rows);
+            let (first, last) = (RAMP.chars().next(), RAMP.chars().last());
+            assert!(row.chars().any(|ch| Some(ch) == first), "{:?}", rows);
+            assert!(row.chars().any(|ch| Some(ch) == last), "{:?}", rows);
+        }
+    }
+
+    #[test]
+    fn files_with_too_many_chunks_are_rejected() {
+        let mut chunks = vec![ihdr(1, 1, 8, 0, false)];
+        chunks.extend((0..10).map(|_| chunk(b"tIME", &[7, 234, 10, 14, 12, 0, 0])));
+        chunks.extend([idat(&[0, 128]), chunk(b"IEND", &[])]);
+        let bytes = png(&chunks);
+        assert!(decode(&bytes).is_ok());
+
+        let opts = DecodeOptions {
+            max_chunks: Some(8),
+            ..Default::default()
+        };
+        let err = decode_with(&bytes, &opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "file has more than 8 chunks");
+    }
+
+    #[test]
+    fn files_convert_to_ascii_in_one_call() {
+        let path = temp_file("file_to_ascii.png", &sample_png());
+        let ascii = file_to_ascii(&path).unwrap();
+        let art = img(&sample_png()).to_ascii_string(&RenderOptions::default());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(ascii.lines().count() > 1, "{:?}", ascii);
+        assert!(ascii.lines().all(|line| !line.is_empty()));
+        assert_eq!(ascii, art);
+        assert!(file_to_ascii(&path).is_err());
+    }
+
+    #[test]
+    fn cache_hits_are_counted_per_get() {
+        let first = temp_file("cache_first.png", &sample_png());
+        let second = temp_file("cache_second.png", &simple_png(1, 1, 8, 0, false, &[0, 9]));
+        let mut cache = ImageCache::new();
+
+        for _ in 0..3 {
+            assert_eq!(cache.get(&first).unwrap().width, 5);
+            assert_eq!(cache.get(&second).unwrap().data, [9]);
+        }
+        assert_eq!((cache.hits(), cache.len()), (4, 2));
+
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert!(cache.get(&first).is_err());
+        assert_eq!((cache.hits(), cache.len()), (4, 2));
     }
 }
@@ -1,27 +1,129 @@
-use png_to_ascii::Img;
-use std::{env, io};
+use png_to_ascii::{DecodeOptions, Img, RenderOptions, RenderTarget};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+const USAGE: &str = "ERR: Usage: png_to_ascii [--watch] <path/to/image> | --sheet <path/to/dir>";
+
+/// how often the file is checked for changes in watch mode
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// size in characters of each image on a contact sheet, and how many images
+/// go side by side
+const SHEET_CELL: (usize, usize) = (40, 20);
+const SHEET_COLUMNS: usize = 3;
 
 fn main() -> io::Result<()> {
     let mut args = env::args();
     args.next();
 
-    let file = args
-        .next()
-        .expect("ERR: Usage: png_to_ascii <path/to/image>");
-
-    let image = Img::new(&file)?;
-    println!(
-        "<html>
-    <body>
-        <div style=\"line-height: 10px; font-size: 14px\">
-            <pre>"
-    );
-    image.display();
-    println!(
-        "</pre>
-        </div>
-    </body>
-</html>"
-    );
+    let file = args.next().expect(USAGE);
+    match file.as_str() {
+        "--watch" => return watch(&args.next().expect(USAGE)),
+        "--sheet" => return sheet(&args.next().expect(USAGE)),
+        _ => {}
+    }
+
+    // progress is only shown to a person watching the terminal
+    let opts = DecodeOptions::for_target(RenderTarget::Html);
+    let image = if io::stderr().is_terminal() {
+        let image = Img::with_progress(&file, &opts, |percent| eprint!("\rdecoding {}%", percent));
+        eprintln!();
+        image?
+    } else {
+        Img::with_options(&file, &opts)?
+    };
+
+    image.render_html(&mut io::stdout(), &RenderOptions::default())?;
     Ok(())
 }
+
+/// Renders the art to the terminal again every time the file changes, moving
+/// the cursor home and clearing the screen so each frame replaces the last.
+/// When stdout is not a terminal the frames are appended as plain text.
+fn watch(file: &str) -> io::Result<()> {
+    let opts = DecodeOptions::for_target(RenderTarget::Terminal);
+    let ansi = io::stdout().is_terminal();
+    let render_opts = RenderOptions {
+        no_ansi: !ansi,
+        ..Default::default()
+    };
+    let mut rendered = None;
+    loop {
+        let modified = fs::metadata(file)?.modified()?;
+        if rendered != Some(modified) {
+            rendered = Some(modified);
+
+            let mut stdout = io::stdout().lock();
+            if ansi {
+                write!(stdout, "\x1b[H\x1b[J")?;
+            }
+            // the file may be caught half written, the next change fixes it
+            match Img::with_options(file, &opts) {
+                Ok(image) => {
+                    image.render(&mut stdout, &render_opts)?;
+                }
+                Err(err) => writeln!(stdout, "ERR: {}", err)?,
+            }
+            stdout.flush()?;
+        }
+
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Renders every PNG in `dir` as a contact sheet, a grid of equally sized
+/// cells each labeled with its file name. Files that fail to decode are
+/// skipped with a warning.
+fn sheet(dir: &str) -> io::Result<()> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+    });
+    paths.sort();
+
+    let opts = DecodeOptions::for_target(RenderTarget::Terminal);
+    let (cols, rows) = SHEET_CELL;
+    let mut cells = Vec::new();
+    for path in &paths {
+        let image = match Img::with_options(&path.to_string_lossy(), &opts) {
+            Ok(image) => image,
+            Err(err) => {
+                eprintln!("WARN: skipping {}: {}", path.display(), err);
+                continue;
+            }
+        };
+
+        let mut art = Vec::new();
+        image.render_canvas(&mut art, &RenderOptions::default(), cols, rows)?;
+        let mut lines: Vec<String> = String::from_utf8_lossy(&art)
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        lines.push(label(path, cols));
+        cells.push(lines);
+    }
+
+    let mut stdout = io::stdout().lock();
+    for row in cells.chunks(SHEET_COLUMNS) {
+        for line in 0..=rows {
+            let line: Vec<&str> = row.iter().map(|cell| cell[line].as_str()).collect();
+            writeln!(stdout, "{}", line.join("  ").trim_end())?;
+        }
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+/// The file name of `path` cut or padded with spaces to `cols` characters
+fn label(path: &Path, cols: usize) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("{:<cols$}", name.chars().take(cols).collect::<String>())
+}
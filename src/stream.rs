@@ -0,0 +1,498 @@
+use crate::{
+    ascii_row, check_ihdr_order, is_ignored, read_u32_be, unfilter, violation, widen, write_legend,
+    Chunk, ColorType, DecodeOptions, Diagnostic, Image, ImageHelper, RenderOptions,
+    DEFAULT_MAX_CHUNKS, MAX_CHUNK_LEN, PNG_HDR, SIGNATURE_SCAN_LIMIT,
+};
+use flate2::{read::ZlibDecoder, Crc, CrcWriter};
+use std::io::{self, Read, Write};
+
+/// Decodes a PNG from `reader` and writes its ASCII art to `writer` in one
+/// call, see `Image::render_streaming`
+pub fn stream_render<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    opts: &RenderOptions,
+) -> io::Result<(usize, usize)> {
+    Image::render_streaming(reader, &mut writer, opts)
+}
+
+impl Image {
+    /// Decodes a PNG from `reader` and renders it to `w` one output row at a
+    /// time, holding only the two scanlines needed for unfiltering instead of
+    /// the whole image. Interlaced images are the exception: none of their
+    /// scanlines is complete before the last pass, so their image data is
+    /// decoded whole before the first row is written. The output matches
+    /// `Img::render` for the same image, as does the returned number of
+    /// columns and rows, except that `transparent_char`, `block_average`,
+    /// `adaptive_tile`, `background_from_metadata`, `caption_from_metadata`,
+    /// and `transparent_placeholder` are not applied. They need the whole
+    /// image, or chunks that may only come after the image data.
+    pub fn render_streaming<R: Read, W: Write>(
+        reader: R,
+        w: &mut W,
+        opts: &RenderOptions,
+    ) -> io::Result<(usize, usize)> {
+        Self::render_streaming_with_options(reader, w, opts, &DecodeOptions::default())
+    }
+
+    /// Like `render_streaming`, decoding the image with `decode_opts`. The
+    /// output matches `Img::render` on an `Img` decoded with the same options,
+    /// except that `auto_orient`, `zlib_dictionary`, and `verify_filters` are
+    /// not applied either, as they need the whole image data.
+    pub fn render_streaming_with_options<R: Read, W: Write>(
+        reader: R,
+        w: &mut W,
+        opts: &RenderOptions,
+        decode_opts: &DecodeOptions,
+    ) -> io::Result<(usize, usize)> {
+        let dimensions = |width, height| opts.dimensions(width, height);
+        let cells = if opts.full_width_cells { 2 } else { 1 };
+        let mut cols = None;
+        let (_, rows) = Self::stream_rows(reader, opts, decode_opts, &dimensions, &mut |row| {
+            // the width of the border is known once the first row is in
+            if cols.is_none() {
+                let width = row.chars().count();
+                if opts.border {
+                    writeln!(w, "┌{}┐", "─".repeat(width * cells))?;
+                }
+                cols = Some(width);
+            }
+
+            if opts.border {
+                writeln!(w, "│{}│", row)
+            } else if opts.trim_trailing_spaces {
+                writeln!(w, "{}", row.trim_end_matches([' ', '\u{3000}']))
+            } else {
+                writeln!(w, "{}", row)
+            }
+        })?;
+
+        let cols = cols.unwrap_or(0);
+        if opts.border {
+            if rows == 0 {
+                writeln!(w, "┌┐")?;
+            }
+            writeln!(w, "└{}┘", "─".repeat(cols * cells))?;
+        }
+
+        if opts.with_legend {
+            write_legend(w)?;
+        }
+
+        Ok((cols, rows))
+    }
+
+    /// Decodes a PNG from `reader` like `render_streaming`, calling `on_row`
+    /// with each row of the art as soon as the scanlines it covers are
+    /// decoded, so the art can be shown top to bottom while the rest of the
+    /// image is still being read. Each character covers `scale` pixels in
+    /// both directions. Returns the number of columns and rows of the art.
+    pub fn stream_ascii_rows<R: Read, F: FnMut(&str)>(
+        reader: R,
+        scale: usize,
+        on_row: F,
+    ) -> io::Result<(usize, usize)> {
+        Self::stream_ascii_rows_with_options(reader, scale, &DecodeOptions::default(), on_row)
+    }
+
+    /// Like `stream_ascii_rows`, decoding the image with `decode_opts`
+    pub fn stream_ascii_rows_with_options<R: Read, F: FnMut(&str)>(
+        reader: R,
+        scale: usize,
+        decode_opts: &DecodeOptions,
+        mut on_row: F,
+    ) -> io::Result<(usize, usize)> {
+        let scale = scale.max(1);
+        let dimensions =
+            |width: usize, height: usize| (width.div_ceil(scale), height.div_ceil(scale));
+        let opts = RenderOptions::default();
+        Self::stream_rows(reader, &opts, decode_opts, &dimensions, &mut |row| {
+            on_row(row);
+            Ok(())
+        })
+    }
+
+    /// Decodes a PNG from `reader` and passes each row of the art to
+    /// `on_row`, the columns and rows of the art for the image's width and
+    /// height given by `dimensions`
+    fn stream_rows<R: Read>(
+        mut reader: R,
+        opts: &RenderOptions,
+        decode_opts: &DecodeOptions,
+        dimensions: &dyn Fn(usize, usize) -> (usize, usize),
+        on_row: &mut dyn FnMut(&str) -> io::Result<()>,
+    ) -> io::Result<(usize, usize)> {
+        let lenient = decode_opts.lenient;
+        let max_chunks = decode_opts.max_chunks.unwrap_or(DEFAULT_MAX_CHUNKS);
+        let diagnostics = &mut Vec::new();
+        read_signature(&mut reader, decode_opts)?;
+
+        // apply every chunk that preceeds the image data
+        let mut image = Image::empty();
+        let mut chunks = 0;
+        let mut seen_ihdr = false;
+        let idat_len = loop {
+            chunks += 1;
+            if chunks > max_chunks {
+                pngerr!("file has more than {} chunks", max_chunks);
+            }
+
+            let mut header = [0u8; 8];
+            reader.read_exact(&mut header)?;
+            let len = chunk_len(&header)?;
+            check_ihdr_order(&header[4..] == b"IHDR", &mut seen_ihdr)?;
+            if &header[4..] == b"IDAT" {
+                break len;
+            }
+
+            if is_ignored(&header[4..]) {
+                skip_chunk(&mut reader, header, len, lenient, diagnostics)?;
+                continue;
+            }
+
+            let mut helper = read_chunk(&mut reader, header, len, decode_opts)?;
+            let chunk = Chunk::new(&mut helper, diagnostics)?;
+            if let Chunk::Iend = chunk {
+                pngerr!("PNG has no IDAT chunk");
+            }
+            image.apply_chunk(chunk, false, lenient, diagnostics)?;
+        };
+        image.validate(lenient, diagnostics)?;
+
+        let mut crc = Crc::new();
+        crc.update(b"IDAT");
+        let mut idat = IdatReader {
+            inner: reader,
+            remaining: idat_len,
+            crc,
+            done: false,
+            chunks,
+            max_chunks,
+            lenient,
+            diagnostics: Vec::new(),
+        };
+
+        // no scanline of an interlaced image is complete before the last
+        // pass, so its image data is decoded as a whole instead
+        let deinterlaced = if image.interlaced {
+            let mut compressed_data = Vec::new();
+            idat.read_to_end(&mut compressed_data)?;
+            let opts = DecodeOptions {
+                auto_orient: false,
+                ..decode_opts.clone()
+            };
+            let decoded = image.clone().decode_image_data(
+                &compressed_data,
+                &opts,
+                diagnostics,
+                &mut |_| {},
+            )?;
+            Some(decoded.data)
+        } else {
+            None
+        };
+        let mut decoder = ZlibDecoder::new(idat);
+
+        let (width, height) = (image.width as usize, image.height as usize);
+        let format = image.pixel_format();
+        let stride = format.stride(width);
+        let bpp = format.filter_bpp();
+
+        // each sampled scanline is finished as an image of its own, so the
+        // decode options apply to it as they would to the whole image
+        let template = Image {
+            height: 1,
+            text: Vec::new(),
+            ..image
+        };
+        let full_depth = decode_opts.precise_16bit
+            && template.bit_depth == 16
+            && template.color_type == ColorType::Greyscale;
+
+        // the prior scanline followed by the current one
+        let mut window = Vec::with_capacity(stride * 2);
+        let mut filtered = vec![0u8; stride + 1];
+        let mut decoded = 0;
+
+        let (cols, rows) = dimensions(width, height);
+        let vertical_skip = height as f32 / rows as f32;
+        let horizontal_skip = width as f32 / cols as f32;
+        let mut previous: Option<Vec<u16>> = None;
+        let mut current: Option<Vec<u16>> = None;
+        for r in 0..=rows {
+            let next = if r < rows {
+                let y = (r as f32 * vertical_skip) as usize;
+                while deinterlaced.is_none() && decoded <= y {
+                    decoder.read_exact(&mut filtered)?;
+                    if window.len() > stride {
+                        window.drain(..stride);
+                    }
+                    let saturating = decode_opts.saturating_filters;
+                    unfilter(&filtered, stride + 1, 1, bpp, saturating, &mut window)?;
+                    decoded += 1;
+                }
+
+                let raw = match &deinterlaced {
+                    Some(data) => &data[y * stride..(y + 1) * stride],
+                    None => &window[window.len() - stride..],
+                };
+                let samples = full_depth.then(|| {
+                    raw.chunks(2)
+                        .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                        .collect::<Vec<_>>()
+                });
+                let mut scanline = template.clone();
+                scanline.data.extend_from_slice(raw);
+                scanline.finish(decode_opts, diagnostics)?;
+
+                let row: Vec<u16> = (0..cols)
+                    .map(|c| {
+                        let c = (c as f32 * horizontal_skip) as usize;
+                        match &samples {
+                            Some(samples) => samples[c],
+                            None => widen(scanline.grey_at(&scanline.data, c, decode_opts.luma)),
+                        }
+                    })
+                    .collect();
+                Some(row)
+            } else {
+                None
+            };
+
+            if let Some(row) = &current {
+                let above = previous.as_deref().unwrap_or(row);
+                let below = next.as_deref().unwrap_or(row);
+                on_row(&ascii_row([above, row, below], None, opts))?;
+            }
+            previous = current;
+            current = next;
+        }
+
+        Ok((cols, rows))
+    }
+
+    /// Decodes a PNG read sequentially from `reader`, which does not need to
+    /// support seeking. Only the image data is buffered as a whole: every
+    /// other chunk is held just long enough to be applied, and the data of
+    /// chunks that would be thrown away is skipped as it is read.
+    pub fn from_unseekable_reader<R: Read>(reader: R) -> io::Result<Self> {
+        Self::from_unseekable_reader_with_options(reader, &DecodeOptions::default())
+    }
+
+    /// Like `from_unseekable_reader`, decoding the image with `opts`
+    pub fn from_unseekable_reader_with_options<R: Read>(
+        mut reader: R,
+        opts: &DecodeOptions,
+    ) -> io::Result<Self> {
+        let max_chunks = opts.max_chunks.unwrap_or(DEFAULT_MAX_CHUNKS);
+        let diagnostics = &mut Vec::new();
+        read_signature(&mut reader, opts)?;
+
+        let mut image = Image::empty();
+        let mut compressed_data = Vec::new();
+        let mut chunks = 0;
+        let (mut seen_ihdr, mut seen_idat) = (false, false);
+        loop {
+            chunks += 1;
+            if chunks > max_chunks {
+                pngerr!("file has more than {} chunks", max_chunks);
+            }
+
+            let mut header = [0u8; 8];
+            if let Err(err) = reader.read_exact(&mut header) {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    pngerr!("file ends without an IEND chunk");
+                }
+                return Err(err);
+            }
+            let len = chunk_len(&header)?;
+            check_ihdr_order(&header[4..] == b"IHDR", &mut seen_ihdr)?;
+
+            if is_ignored(&header[4..]) {
+                skip_chunk(&mut reader, header, len, opts.lenient, diagnostics)?;
+                continue;
+            }
+
+            let mut helper = read_chunk(&mut reader, header, len, opts)?;
+            match Chunk::new(&mut helper, diagnostics)? {
+                Chunk::Idat(data) => {
+                    compressed_data.extend(data);
+                    seen_idat = true;
+                }
+                Chunk::Iend => break,
+                chunk => image.apply_chunk(
+                    chunk,
+                    !compressed_data.is_empty(),
+                    opts.lenient,
+                    diagnostics,
+                )?,
+            }
+        }
+        if !seen_idat {
+            pngerr!("PNG has no IDAT chunk");
+        }
+        image.validate(opts.lenient, diagnostics)?;
+
+        let mut image =
+            image.decode_image_data(&compressed_data, opts, diagnostics, &mut |_| {})?;
+        image.finish(opts, diagnostics)?;
+
+        Ok(image)
+    }
+}
+
+/// Reads the PNG signature from the start of `reader`, or from within its
+/// first few KB with `opts.scan_for_signature`, leaving the reader at the
+/// first chunk
+fn read_signature<R: Read>(reader: &mut R, opts: &DecodeOptions) -> io::Result<()> {
+    let mut window = [0u8; 8];
+    reader.read_exact(&mut window)?;
+    if !opts.scan_for_signature {
+        if window != PNG_HDR {
+            pngerr!("invalid PNG signature");
+        }
+        return Ok(());
+    }
+
+    let mut start = 0;
+    while window != PNG_HDR {
+        start += 1;
+        let mut byte = [0u8];
+        if start == SIGNATURE_SCAN_LIMIT || reader.read(&mut byte)? == 0 {
+            pngerr!("PNG signature not found");
+        }
+        window.copy_within(1.., 0);
+        window[7] = byte[0];
+    }
+
+    Ok(())
+}
+
+/// The data length in the chunk `header`, which the spec limits to 2^31-1
+/// bytes
+fn chunk_len(header: &[u8; 8]) -> io::Result<usize> {
+    let len = read_u32_be(header, 0)? as usize;
+    if len > MAX_CHUNK_LEN {
+        pngerr!(
+            "{} chunk declares {} bytes of data, more than the {} allowed",
+            String::from_utf8_lossy(&header[4..]),
+            len,
+            MAX_CHUNK_LEN
+        );
+    }
+
+    Ok(len)
+}
+
+/// Reads the data and CRC of the chunk with `header`, whose data is `len`
+/// bytes long, so it can be parsed on its own. The buffer only grows with
+/// the bytes that arrive, so a bogus length can not make it allocate more
+/// than the stream holds.
+fn read_chunk<R: Read>(
+    reader: &mut R,
+    header: [u8; 8],
+    len: usize,
+    opts: &DecodeOptions,
+) -> io::Result<ImageHelper> {
+    let mut data = header.to_vec();
+    let needed = len as u64 + 4;
+    if reader.take(needed).read_to_end(&mut data)? as u64 != needed {
+        pngerr!(
+            "file ends in the middle of the {} chunk, which needs {} bytes",
+            String::from_utf8_lossy(&header[4..]),
+            len + 12
+        );
+    }
+
+    Ok(ImageHelper::at(data, 0, opts))
+}
+
+/// Skips the data of the chunk with `header`, whose content would be thrown
+/// away, without buffering it. Its CRC is still verified.
+fn skip_chunk<R: Read>(
+    reader: &mut R,
+    header: [u8; 8],
+    len: usize,
+    lenient: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> io::Result<()> {
+    let mut data = CrcWriter::new(io::sink());
+    data.write_all(&header[4..])?;
+    let copied = io::copy(&mut reader.take(len as u64), &mut data)?;
+    let mut crc = [0u8; 4];
+    if copied != len as u64 || reader.read_exact(&mut crc).is_err() {
+        pngerr!(
+            "file ends in the middle of the {} chunk, which needs {} bytes",
+            String::from_utf8_lossy(&header[4..]),
+            len + 12
+        );
+    }
+
+    if u32::from_be_bytes(crc) != data.crc().sum() {
+        let chunk_type = String::from_utf8_lossy(&header[4..]);
+        let message = format!("{} chunk has a CRC mismatch", chunk_type);
+        violation(lenient, diagnostics, Some(&chunk_type), message)?;
+    }
+    Ok(())
+}
+
+/// Reads the payload of consecutive IDAT chunks as one stream, verifying the
+/// CRC of each chunk and stopping at the first chunk that is not an IDAT
+struct IdatReader<R> {
+    inner: R,
+    remaining: usize,
+    crc: Crc,
+    done: bool,
+
+    /// number of chunks read so far, counting the IDAT chunks, and the most
+    /// that may be read
+    chunks: usize,
+    max_chunks: usize,
+
+    /// CRC mismatches are recorded in `diagnostics` instead of failing
+    lenient: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<R: Read> Read for IdatReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.done {
+                return Ok(0);
+            }
+
+            let mut crc = [0u8; 4];
+            self.inner.read_exact(&mut crc)?;
+            if u32::from_be_bytes(crc) != self.crc.sum() {
+                let message = "IDAT chunk has a CRC mismatch".to_owned();
+                violation(self.lenient, &mut self.diagnostics, Some("IDAT"), message)?;
+            }
+
+            let mut header = [0u8; 8];
+            self.inner.read_exact(&mut header)?;
+            if &header[4..] != b"IDAT" {
+                self.done = true;
+                return Ok(0);
+            }
+
+            self.chunks += 1;
+            if self.chunks > self.max_chunks {
+                pngerr!("file has more than {} chunks", self.max_chunks);
+            }
+
+            self.remaining = chunk_len(&header)?;
+            self.crc = Crc::new();
+            self.crc.update(b"IDAT");
+        }
+
+        let len = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 && len > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.crc.update(&buf[..read]);
+        self.remaining -= read;
+        Ok(read)
+    }
+}
@@ -0,0 +1,201 @@
+//! Runs the binary on PNG fixtures written to a temporary directory
+
+use flate2::{write::ZlibEncoder, Compression, Crc};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_png_to_ascii");
+
+/// An 8-bit greyscale PNG of `width`x`height` pixels, each given by `grey`
+fn grey_png(width: u32, height: u32, grey: impl Fn(u32, u32) -> u8) -> Vec<u8> {
+    let mut filtered = Vec::new();
+    for y in 0..height {
+        filtered.push(0);
+        filtered.extend((0..width).map(|x| grey(x, y)));
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&filtered).unwrap();
+
+    let mut ihdr = width.to_be_bytes().to_vec();
+    ihdr.extend(height.to_be_bytes());
+    ihdr.extend([8, 0, 0, 0, 0]);
+    let chunks = [
+        (b"IHDR", ihdr),
+        (b"IDAT", encoder.finish().unwrap()),
+        (b"IEND", Vec::new()),
+    ];
+
+    let mut bytes = vec![137, 80, 78, 71, 13, 10, 26, 10];
+    for (kind, data) in chunks {
+        let mut crc = Crc::new();
+        crc.update(kind);
+        crc.update(&data);
+        bytes.extend((data.len() as u32).to_be_bytes());
+        bytes.extend(kind);
+        bytes.extend(&data);
+        bytes.extend(crc.sum().to_be_bytes());
+    }
+    bytes
+}
+
+/// An empty directory of the temporary directory for the test `name`
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("png_to_ascii_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(BIN).args(args).output().unwrap()
+}
+
+/// Runs `command` watching `file` until `frames` counts a first frame in its
+/// output, then replaces the file with `next` and runs it until a second
+/// frame starts, returning the output
+fn watch(
+    mut command: Command,
+    file: &Path,
+    next: &[u8],
+    frames: impl Fn(&[u8]) -> usize,
+) -> Vec<u8> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let reader = {
+        let output = Arc::clone(&output);
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(read @ 1..) = stdout.read(&mut buf) {
+                output.lock().unwrap().extend(&buf[..read]);
+            }
+        })
+    };
+    let wait_for = |count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while frames(&output.lock().unwrap()) < count {
+            assert!(Instant::now() < deadline, "no frame {} in time", count);
+            thread::sleep(Duration::from_millis(20));
+        }
+    };
+
+    wait_for(1);
+    // a modification time well past the first one, however coarse the
+    // file system's timestamps are
+    fs::write(file, next).unwrap();
+    let modified = SystemTime::now() + Duration::from_secs(60);
+    let handle = fs::File::options().write(true).open(file).unwrap();
+    handle.set_modified(modified).unwrap();
+    wait_for(2);
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    reader.join().unwrap();
+    let output = output.lock().unwrap();
+    output.clone()
+}
+
+#[test]
+fn sheet_labels_every_png_and_skips_broken_ones() {
+    let dir = temp_dir("sheet");
+    fs::write(dir.join("a.png"), grey_png(32, 16, |x, _| (x * 8) as u8)).unwrap();
+    fs::write(dir.join("b.png"), grey_png(16, 16, |_, y| (y * 16) as u8)).unwrap();
+    fs::write(dir.join("broken.png"), b"not a png").unwrap();
+    fs::write(dir.join("notes.txt"), b"not an image either").unwrap();
+
+    let output = run(&["--sheet", dir.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    // both images share a row of the sheet, labeled underneath
+    let labels: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.contains(".png"))
+        .collect();
+    assert_eq!(labels.len(), 1, "{}", stdout);
+    assert!(labels[0].starts_with("a.png") && labels[0].contains("b.png"));
+    assert!(stdout.lines().count() > 20);
+    assert!(!stdout.contains("broken") && !stdout.contains("notes"));
+    assert!(!stdout.contains('\x1b'));
+
+    assert!(stderr.contains("WARN: skipping"), "{}", stderr);
+    assert!(stderr.contains("broken.png"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn html_output_has_no_progress_when_stderr_is_not_a_terminal() {
+    let dir = temp_dir("progress");
+    let file = dir.join("large.png");
+    fs::write(&file, grey_png(1024, 1024, |x, y| (x ^ y) as u8)).unwrap();
+
+    let output = run(&[file.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("<html>"), "{}", stdout);
+    assert!(stdout.trim_end().ends_with("</html>"));
+    assert!(output.stderr.is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// starts every frame of watch mode in a terminal
+const CLEAR: &str = "\x1b[H\x1b[J";
+
+// a pseudo terminal for the binary, by way of util-linux's script
+#[cfg(target_os = "linux")]
+#[test]
+fn watch_clears_the_terminal_before_each_frame() {
+    let dir = temp_dir("watch_terminal");
+    let file = dir.join("watched.png");
+    fs::write(&file, grey_png(8, 8, |_, _| 0)).unwrap();
+
+    let mut command = Command::new("script");
+    let shell = format!("{} --watch {}", BIN, file.display());
+    command.args(["-qec", &shell, "/dev/null"]);
+    let next = grey_png(8, 8, |_, _| 255);
+    let output = watch(command, &file, &next, |output| {
+        String::from_utf8_lossy(output).matches(CLEAR).count()
+    });
+
+    let output = String::from_utf8_lossy(&output);
+    assert!(output.starts_with(CLEAR), "{:?}", output);
+    let frames: Vec<_> = output.split(CLEAR).skip(1).collect();
+    assert!(frames.len() >= 2);
+    assert!(frames[0].lines().count() > 1, "{:?}", frames[0]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn piped_watch_frames_have_no_escapes() {
+    let dir = temp_dir("watch_piped");
+    let file = dir.join("watched.png");
+    fs::write(&file, grey_png(8, 8, |_, _| 0)).unwrap();
+
+    // frames are the default 150 rows and follow each other plainly
+    let mut command = Command::new(BIN);
+    command.arg("--watch").arg(&file);
+    let next = grey_png(8, 8, |_, _| 255);
+    let output = watch(command, &file, &next, |output| {
+        output.iter().filter(|byte| **byte == b'\n').count() / 150
+    });
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(!output.contains('\x1b'), "{:?}", output);
+    let lines: Vec<_> = output.lines().collect();
+    assert!(lines.len() >= 300);
+    assert_ne!(lines[0], lines[150]);
+    assert!(lines[..150].iter().all(|line| *line == lines[0]));
+    fs::remove_dir_all(dir).unwrap();
+}